@@ -0,0 +1,675 @@
+use crate::vtt::Timestamp;
+use regex::Regex;
+use std::str::FromStr;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// A start tag in WebVTT cue text, e.g. `<c.yellow.bg_blue>` or `<v Fred>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CueTag {
+    pub name: String,
+    pub classes: Vec<String>,
+    pub annotation: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CueToken {
+    Text(String),
+    StartTag(CueTag),
+    EndTag(String),
+    Timestamp(Timestamp),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CueNode {
+    Text(String),
+    Timestamp(Timestamp),
+    Element(CueTag, Vec<CueNode>),
+}
+
+/// A whitespace-separated word of cue text, along with any markup in or around it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Word {
+    pub tokens: Vec<CueToken>,
+    /// The text of the word without markup.
+    pub text: String,
+}
+
+static TAG_NAMES: [&str; 8] = ["c", "i", "b", "u", "ruby", "rt", "v", "lang"];
+
+/// Split cue text into text, tags and timestamps, decoding any character references.
+///
+/// Unrecognised tags are dropped, as they are by the WebVTT cue text parsing rules.
+pub fn tokenize(text: &str) -> Vec<CueToken> {
+    let mut tokens = vec![];
+    let mut buffer = String::new();
+    let mut rest = text;
+
+    while let Some(c) = rest.chars().next() {
+        if c == '<' {
+            let end = rest.find('>').unwrap_or(rest.len());
+            if !buffer.is_empty() {
+                tokens.push(CueToken::Text(std::mem::take(&mut buffer)));
+            }
+            if let Some(token) = parse_tag(&rest[1..end]) {
+                tokens.push(token);
+            }
+            rest = &rest[(end + 1).min(rest.len())..];
+        } else if c == '&' {
+            let (decoded, len) = decode_character_reference(rest);
+            buffer.push_str(&decoded);
+            rest = &rest[len..];
+        } else {
+            buffer.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    if !buffer.is_empty() {
+        tokens.push(CueToken::Text(buffer));
+    }
+    tokens
+}
+
+fn parse_tag(tag: &str) -> Option<CueToken> {
+    if let Some(name) = tag.strip_prefix('/') {
+        let name = name.split(['.', ' ', '\t']).next().unwrap_or("").trim();
+        return Some(CueToken::EndTag(name.to_string()));
+    }
+    if tag.starts_with(|c: char| c.is_ascii_digit()) {
+        return Timestamp::from_str(tag).ok().map(CueToken::Timestamp);
+    }
+
+    let (head, annotation) = match tag.split_once(|c: char| c.is_whitespace()) {
+        Some((head, annotation)) => (head, Some(annotation)),
+        None => (tag, None),
+    };
+    let mut parts = head.split('.');
+    let name = parts.next().unwrap_or("");
+    if !TAG_NAMES.contains(&name) {
+        return None;
+    }
+    let annotation = annotation
+        .map(|a| a.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|a| !a.is_empty());
+    Some(CueToken::StartTag(CueTag {
+        name: name.to_string(),
+        classes: parts.filter(|c| !c.is_empty()).map(String::from).collect(),
+        annotation,
+    }))
+}
+
+/// Decode the character reference at the start of `text`, returning the decoded text and the number of bytes consumed.
+fn decode_character_reference(text: &str) -> (String, usize) {
+    let re = Regex::new(r"^&(#[0-9]+|#[xX][0-9a-fA-F]+|[a-zA-Z]+);").unwrap();
+    if let Some(caps) = re.captures(text) {
+        let len = caps[0].len();
+        let decoded = match &caps[1] {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some('\u{00A0}'),
+            "lrm" => Some('\u{200E}'),
+            "rlm" => Some('\u{200F}'),
+            reference if reference.starts_with("#x") || reference.starts_with("#X") => {
+                u32::from_str_radix(&reference[2..], 16)
+                    .ok()
+                    .and_then(char::from_u32)
+            }
+            reference if reference.starts_with('#') => {
+                reference[1..].parse().ok().and_then(char::from_u32)
+            }
+            _ => None,
+        };
+        if let Some(decoded) = decoded {
+            return (decoded.to_string(), len);
+        }
+    }
+    ("&".to_string(), 1)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\u{00A0}', "&nbsp;")
+}
+
+fn serialize_start_tag(tag: &CueTag) -> String {
+    let mut serialized = format!("<{}", tag.name);
+    for class in tag.classes.iter() {
+        serialized.push_str(&format!(".{}", class));
+    }
+    if let Some(annotation) = &tag.annotation {
+        serialized.push_str(&format!(" {}", annotation));
+    }
+    serialized.push('>');
+    serialized
+}
+
+/// Serialise tokens back to WebVTT cue text.
+pub fn serialize_tokens(tokens: &[CueToken]) -> String {
+    tokens
+        .iter()
+        .map(|token| match token {
+            CueToken::Text(text) => escape(text),
+            CueToken::StartTag(tag) => serialize_start_tag(tag),
+            CueToken::EndTag(name) => format!("</{}>", name),
+            CueToken::Timestamp(timestamp) => format!("<{}>", timestamp),
+        })
+        .collect()
+}
+
+/// Parse cue text into a tree of nodes.
+pub fn parse(text: &str) -> Vec<CueNode> {
+    let mut root: Vec<CueNode> = vec![];
+    let mut open: Vec<(CueTag, Vec<CueNode>)> = vec![];
+
+    fn close(root: &mut Vec<CueNode>, open: &mut Vec<(CueTag, Vec<CueNode>)>) {
+        if let Some((tag, children)) = open.pop() {
+            let element = CueNode::Element(tag, children);
+            match open.last_mut() {
+                Some((_, parent_children)) => parent_children.push(element),
+                None => root.push(element),
+            }
+        }
+    }
+
+    for token in tokenize(text) {
+        let node = match token {
+            CueToken::Text(text) => CueNode::Text(text),
+            CueToken::Timestamp(timestamp) => CueNode::Timestamp(timestamp),
+            CueToken::StartTag(tag) => {
+                open.push((tag, vec![]));
+                continue;
+            }
+            CueToken::EndTag(name) => {
+                // End tags which don't match the current element are ignored, except that </ruby> also closes an <rt>
+                let current = open.last().map(|(tag, _)| tag.name.as_str());
+                if current == Some(name.as_str()) {
+                    close(&mut root, &mut open);
+                } else if name == "ruby" && current == Some("rt") {
+                    close(&mut root, &mut open);
+                    close(&mut root, &mut open);
+                }
+                continue;
+            }
+        };
+        match open.last_mut() {
+            Some((_, children)) => children.push(node),
+            None => root.push(node),
+        }
+    }
+    while !open.is_empty() {
+        close(&mut root, &mut open);
+    }
+    root
+}
+
+/// Serialise a tree of nodes back to WebVTT cue text.
+pub fn serialize(nodes: &[CueNode]) -> String {
+    nodes
+        .iter()
+        .map(|node| match node {
+            CueNode::Text(text) => escape(text),
+            CueNode::Timestamp(timestamp) => format!("<{}>", timestamp),
+            CueNode::Element(tag, children) => format!(
+                "{}{}</{}>",
+                serialize_start_tag(tag),
+                serialize(children),
+                tag.name
+            ),
+        })
+        .collect()
+}
+
+/// Merge adjacent elements with identical tags (e.g. `<i>a</i> <i>b</i>` becomes `<i>a b</i>`).
+fn merge_adjacent(nodes: Vec<CueNode>) -> Vec<CueNode> {
+    let mut merged: Vec<CueNode> = vec![];
+    for node in nodes {
+        let node = match node {
+            CueNode::Element(tag, children) => CueNode::Element(tag, merge_adjacent(children)),
+            node => node,
+        };
+        if let CueNode::Element(tag, children) = &node {
+            // Look back past any whitespace for an identical element
+            let whitespace = match merged.last() {
+                Some(CueNode::Text(text)) if text.trim().is_empty() => 1,
+                _ => 0,
+            };
+            if merged.len() > whitespace {
+                let previous = merged.len() - 1 - whitespace;
+                if let CueNode::Element(previous_tag, _) = &merged[previous] {
+                    if previous_tag == tag {
+                        let between: Vec<CueNode> = merged.drain(previous + 1..).collect();
+                        if let Some(CueNode::Element(_, previous_children)) = merged.last_mut() {
+                            let mut combined = std::mem::take(previous_children);
+                            combined.extend(between);
+                            combined.extend(children.clone());
+                            *previous_children = merge_adjacent(combined);
+                        }
+                        continue;
+                    }
+                }
+            }
+        }
+        merged.push(node);
+    }
+    merged
+}
+
+/// Tidy cue text by balancing its tags and merging adjacent identical elements.
+pub fn normalise(text: &str) -> String {
+    serialize(&merge_adjacent(parse(text)))
+}
+
+/// The width of `text` as displayed, counting each grapheme cluster as one column, or two if it is wide (e.g. CJK characters and emoji).
+pub fn display_width(text: &str) -> usize {
+    text.graphemes(true)
+        .map(|grapheme| grapheme.width().clamp(1, 2))
+        .sum()
+}
+
+/// The text of the tokens, without any markup.
+pub fn plain_text(tokens: &[CueToken]) -> String {
+    tokens
+        .iter()
+        .filter_map(|token| match token {
+            CueToken::Text(text) => Some(text.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Split tokens at the given byte offsets into their plain text (see `plain_text`).
+///
+/// End tags at a split point stay with the preceding piece, while start tags and timestamps go with the following one.
+pub fn split_at(tokens: &[CueToken], offsets: &[usize]) -> Vec<Vec<CueToken>> {
+    let mut pieces: Vec<Vec<CueToken>> = vec![vec![]];
+    let mut offsets = offsets.iter().copied().peekable();
+    let mut position = 0;
+
+    for token in tokens {
+        match token {
+            CueToken::Text(text) => {
+                let mut text = text.as_str();
+                while let Some(offset) = offsets.peek().copied() {
+                    if offset > position + text.len() {
+                        break;
+                    }
+                    let (before, after) = text.split_at(offset - position);
+                    if !before.is_empty() {
+                        pieces
+                            .last_mut()
+                            .unwrap()
+                            .push(CueToken::Text(before.to_string()));
+                    }
+                    pieces.push(vec![]);
+                    offsets.next();
+                    position = offset;
+                    text = after;
+                }
+                if !text.is_empty() {
+                    pieces
+                        .last_mut()
+                        .unwrap()
+                        .push(CueToken::Text(text.to_string()));
+                }
+                position += text.len();
+            }
+            CueToken::EndTag(_) if pieces.len() > 1 && pieces.last().unwrap().is_empty() => {
+                let last = pieces.len() - 1;
+                pieces[last - 1].push(token.clone());
+            }
+            _ => pieces.last_mut().unwrap().push(token.clone()),
+        }
+    }
+    pieces
+}
+
+/// Trim whitespace from the start of the first text token and the end of the last.
+pub fn trim(tokens: &mut [CueToken]) {
+    if let Some(CueToken::Text(text)) = tokens
+        .iter_mut()
+        .find(|token| matches!(token, CueToken::Text(_)))
+    {
+        *text = text.trim_start().to_string();
+    }
+    if let Some(CueToken::Text(text)) = tokens
+        .iter_mut()
+        .rev()
+        .find(|token| matches!(token, CueToken::Text(_)))
+    {
+        *text = text.trim_end().to_string();
+    }
+}
+
+/// Group tokens into words, attaching each tag to the word it is next to.
+pub fn words(tokens: &[CueToken]) -> Vec<Word> {
+    let mut words: Vec<Word> = vec![];
+    let mut word = Word {
+        tokens: vec![],
+        text: String::new(),
+    };
+
+    for token in tokens {
+        match token {
+            CueToken::Text(text) => {
+                let mut pieces = text.split(char::is_whitespace).peekable();
+                while let Some(piece) = pieces.next() {
+                    if !piece.is_empty() {
+                        word.tokens.push(CueToken::Text(piece.to_string()));
+                        word.text.push_str(piece);
+                    }
+                    if pieces.peek().is_some() && !word.text.is_empty() {
+                        words.push(std::mem::replace(
+                            &mut word,
+                            Word {
+                                tokens: vec![],
+                                text: String::new(),
+                            },
+                        ));
+                    }
+                }
+            }
+            // An end tag following whitespace belongs to the previous word
+            CueToken::EndTag(_) if word.tokens.is_empty() && !words.is_empty() => {
+                words.last_mut().unwrap().tokens.push(token.clone());
+            }
+            _ => word.tokens.push(token.clone()),
+        }
+    }
+    if !word.text.is_empty() {
+        words.push(word);
+    } else if let Some(last) = words.last_mut() {
+        // Keep any trailing end tags, but drop start tags which no longer contain any text
+        last.tokens.extend(
+            word.tokens
+                .into_iter()
+                .filter(|token| matches!(token, CueToken::EndTag(_))),
+        );
+    }
+    words
+}
+
+/// Update the stack of open tags for the given tokens.
+pub fn update_open_tags(open_tags: &mut Vec<CueTag>, tokens: &[CueToken]) {
+    for token in tokens {
+        match token {
+            CueToken::StartTag(tag) => open_tags.push(tag.clone()),
+            CueToken::EndTag(name) => {
+                if let Some(position) = open_tags.iter().rposition(|tag| &tag.name == name) {
+                    open_tags.truncate(position);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Serialise groups of tokens as standalone cue text, closing any tags left open at the end of each group and re-opening them at the start of the next.
+pub fn serialize_balanced(groups: &[Vec<CueToken>]) -> Vec<String> {
+    let mut open_tags: Vec<CueTag> = vec![];
+    groups
+        .iter()
+        .map(|group| {
+            let mut tokens: Vec<CueToken> =
+                open_tags.iter().cloned().map(CueToken::StartTag).collect();
+            tokens.extend(group.iter().cloned());
+            update_open_tags(&mut open_tags, group);
+            tokens.extend(
+                open_tags
+                    .iter()
+                    .rev()
+                    .map(|tag| CueToken::EndTag(tag.name.clone())),
+            );
+            serialize_tokens(&tokens)
+        })
+        .collect()
+}
+
+pub(crate) fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Decode the character references in HTML text, e.g. `R&amp;D` to `R&D`.
+pub(crate) fn unescape_html(text: &str) -> String {
+    let mut unescaped = String::new();
+    let mut rest = text;
+    while let Some(i) = rest.find('&') {
+        unescaped.push_str(&rest[..i]);
+        let (decoded, len) = decode_character_reference(&rest[i..]);
+        unescaped.push_str(&decoded);
+        rest = &rest[i + len..];
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+/// Convert tokens to HTML for translation, replacing each tag and timestamp with a numbered `<span>` so that it passes through untranslated.
+///
+/// The tags and timestamps are added to `markup`, indexed by span number, so that they can be restored by `from_html`.
+pub fn to_html(tokens: &[CueToken], markup: &mut Vec<CueToken>) -> String {
+    tokens
+        .iter()
+        .map(|token| match token {
+            CueToken::Text(text) => escape_html(text),
+            CueToken::StartTag(_) => {
+                markup.push(token.clone());
+                format!("<span id=\"{}\">", markup.len() - 1)
+            }
+            CueToken::EndTag(_) => "</span>".to_string(),
+            CueToken::Timestamp(_) => {
+                markup.push(token.clone());
+                format!("<span id=\"{}\"></span>", markup.len() - 1)
+            }
+        })
+        .collect()
+}
+
+/// Convert translated HTML produced from `to_html` back to tokens.
+pub fn from_html(html: &str, markup: &[CueToken]) -> Vec<CueToken> {
+    from_html_with_offsets(html, markup).0
+}
+
+/// As `from_html`, but also return the character index in `html` of each character of the tokens' plain text.
+pub fn from_html_with_offsets(html: &str, markup: &[CueToken]) -> (Vec<CueToken>, Vec<usize>) {
+    let span_re = Regex::new(r#"^span\s+id\s*=\s*["']?([0-9]+)["']?\s*$"#).unwrap();
+    let mut tokens = vec![];
+    let mut buffer = String::new();
+    let mut open_spans: Vec<Option<usize>> = vec![];
+    let mut offsets = vec![];
+    let mut rest = html;
+
+    while let Some(c) = rest.chars().next() {
+        if c == '<' {
+            let end = rest.find('>').unwrap_or(rest.len());
+            let tag = &rest[1..end];
+            rest = &rest[(end + 1).min(rest.len())..];
+            if !buffer.is_empty() {
+                tokens.push(CueToken::Text(std::mem::take(&mut buffer)));
+            }
+            if tag.trim() == "/span" {
+                if let Some(Some(CueToken::StartTag(tag))) =
+                    open_spans.pop().flatten().map(|id| markup.get(id))
+                {
+                    tokens.push(CueToken::EndTag(tag.name.clone()));
+                }
+            } else if let Some(caps) = span_re.captures(tag.trim()) {
+                let id = caps[1]
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|id| *id < markup.len());
+                if let Some(id) = id {
+                    tokens.push(markup[id].clone());
+                }
+                open_spans.push(id);
+            }
+            // Any other tags weren't ours, so are dropped
+        } else if c == '&' {
+            let (decoded, len) = decode_character_reference(rest);
+            let offset = html[..html.len() - rest.len()].chars().count();
+            offsets.extend(decoded.chars().map(|_| offset));
+            buffer.push_str(&decoded);
+            rest = &rest[len..];
+        } else {
+            offsets.push(html[..html.len() - rest.len()].chars().count());
+            buffer.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    if !buffer.is_empty() {
+        tokens.push(CueToken::Text(buffer));
+    }
+    (tokens, offsets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(name: &str, classes: &[&str], annotation: Option<&str>) -> CueTag {
+        CueTag {
+            name: name.to_string(),
+            classes: classes.iter().map(|c| c.to_string()).collect(),
+            annotation: annotation.map(String::from),
+        }
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokenize("<v.loud Fred  Bloggs>Hi &amp; <c.a.b>bye</c><00:00:01.500>!<foo>"),
+            vec![
+                CueToken::StartTag(tag("v", &["loud"], Some("Fred Bloggs"))),
+                CueToken::Text("Hi & ".to_string()),
+                CueToken::StartTag(tag("c", &["a", "b"], None)),
+                CueToken::Text("bye".to_string()),
+                CueToken::EndTag("c".to_string()),
+                CueToken::Timestamp(Timestamp::from_millis(1500)),
+                CueToken::Text("!".to_string()),
+            ]
+        );
+        assert_eq!(
+            tokenize("a &lt;&#65;&#x42;&bogus; &"),
+            vec![CueToken::Text("a <AB&bogus; &".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_parse_tree() {
+        assert_eq!(
+            parse("<ruby>漢<rt>kan</ruby> <i>x</b>y"),
+            vec![
+                CueNode::Element(
+                    tag("ruby", &[], None),
+                    vec![
+                        CueNode::Text("漢".to_string()),
+                        CueNode::Element(
+                            tag("rt", &[], None),
+                            vec![CueNode::Text("kan".to_string())]
+                        )
+                    ]
+                ),
+                CueNode::Text(" ".to_string()),
+                CueNode::Element(
+                    tag("i", &[], None),
+                    vec![
+                        CueNode::Text("x".to_string()),
+                        CueNode::Text("y".to_string())
+                    ]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_normalise() {
+        assert_eq!(normalise("<i>a</i> <i>b</i>"), "<i>a b</i>");
+        assert_eq!(normalise("<b>a\nb"), "<b>a\nb</b>");
+        assert_eq!(normalise("a </i>&lt;b"), "a &lt;b");
+        assert_eq!(
+            normalise("<v Fred>a</v> <v Jim>b</v>"),
+            "<v Fred>a</v> <v Jim>b</v>"
+        );
+    }
+
+    #[test]
+    fn test_split_at() {
+        let tokens = tokenize("<i>One.</i> Two. <b>Three");
+        let pieces = split_at(&tokens, &[4, 9]);
+        assert_eq!(
+            pieces
+                .iter()
+                .map(|p| serialize_tokens(p))
+                .collect::<Vec<_>>(),
+            vec!["<i>One.</i>", " Two.", " <b>Three"]
+        );
+    }
+
+    #[test]
+    fn test_words() {
+        let words = words(&tokenize("He said <i>hi</i>. <b> Then </b> left"));
+        assert_eq!(
+            words
+                .iter()
+                .map(|w| serialize_tokens(&w.tokens))
+                .collect::<Vec<_>>(),
+            vec!["He", "said", "<i>hi</i>.", "<b>Then</b>", "left"]
+        );
+        assert_eq!(words[2].text, "hi.");
+    }
+
+    #[test]
+    fn test_serialize_balanced() {
+        let tokens = tokenize("<i>one two</i> three");
+        let pieces = split_at(&tokens, &[4]);
+        assert_eq!(
+            serialize_balanced(&pieces),
+            vec!["<i>one </i>", "<i>two</i> three"]
+        );
+    }
+
+    #[test]
+    fn test_html_round_trip() {
+        let tokens = tokenize("<c.loud>Tom &amp; <00:00:01.000>Jerry</c>");
+        let mut markup = vec![];
+        let html = to_html(&tokens, &mut markup);
+        assert_eq!(
+            html,
+            "<span id=\"0\">Tom &amp; <span id=\"1\"></span>Jerry</span>"
+        );
+        assert_eq!(from_html(&html, &markup), tokens);
+        assert_eq!(
+            serialize_tokens(&from_html(
+                "<span id=\"0\">Jerry <b>et</b> Tom</span><span id=\"9\">!</span>",
+                &markup
+            )),
+            "<c.loud>Jerry et Tom</c>!"
+        );
+    }
+
+    #[test]
+    fn test_display_width() {
+        assert_eq!(display_width("Hello"), 5);
+        assert_eq!(display_width("سلام"), 4);
+        assert_eq!(display_width("خُدا"), 3);
+        assert_eq!(display_width("你好"), 4);
+        assert_eq!(display_width("e\u{301}"), 1);
+        assert_eq!(display_width("👍🏽"), 2);
+        assert_eq!(display_width("👨‍👩‍👧"), 2);
+    }
+
+    #[test]
+    fn test_from_html_offsets() {
+        let (tokens, offsets) = from_html_with_offsets(
+            "<span id=\"0\">a&amp;b</span> c",
+            &[CueToken::StartTag(tag("i", &[], None))],
+        );
+        assert_eq!(serialize_tokens(&tokens), "<i>a&amp;b</i> c");
+        assert_eq!(offsets, vec![13, 14, 19, 27, 28]);
+    }
+}
@@ -0,0 +1,164 @@
+use crate::translate::Language;
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Terms which must be translated in a particular way, such as product names and jargon.
+///
+/// A glossary file has a term and its translation on each line, separated by a comma (or a tab, in a `.tsv` file). Alternatively, a header line of `source` followed by language tags gives a column of translations for each language, which may be left empty for terms that don't need a fixed translation into that language. Lines starting with `#` are ignored.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Glossary {
+    /// The language of each column of translations, or `None` if there's a single column used for all languages.
+    languages: Option<Vec<Language>>,
+    /// Each term, with its translations.
+    entries: Vec<(String, Vec<String>)>,
+}
+
+impl Glossary {
+    pub fn load(path: &Path) -> Result<Glossary> {
+        let delimiter = match path.extension().and_then(|e| e.to_str()) {
+            Some(extension) if extension.eq_ignore_ascii_case("tsv") => b'\t',
+            _ => b',',
+        };
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read glossary file {:?}", path))?;
+        Glossary::parse(&contents, delimiter)
+            .with_context(|| format!("Failed to parse glossary file {:?}", path))
+    }
+
+    pub fn parse(contents: &str, delimiter: u8) -> Result<Glossary> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .delimiter(delimiter)
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .comment(Some(b'#'))
+            .from_reader(contents.as_bytes());
+        let mut languages = None;
+        let mut entries = vec![];
+        for (row_num, row) in reader.records().enumerate() {
+            let row = row?;
+            let line = row.position().map_or(row_num + 1, |p| p.line() as usize);
+            let mut cells = row.iter().map(str::to_string);
+            let term = cells.next().unwrap_or_default();
+            if row_num == 0 && term.eq_ignore_ascii_case("source") {
+                languages = Some(
+                    cells
+                        .map(|tag| tag.parse())
+                        .collect::<Result<Vec<Language>>>()
+                        .with_context(|| format!("Invalid language in header on line {}", line))?,
+                );
+                continue;
+            }
+
+            let translations: Vec<String> = cells.collect();
+            let columns = languages.as_ref().map_or(1, Vec::len);
+            if term.is_empty() || translations.len() != columns {
+                bail!(
+                    "Line {} should have a term and {} translation{}",
+                    line,
+                    columns,
+                    if columns == 1 { "" } else { "s" }
+                );
+            }
+            entries.push((term, translations));
+        }
+        Ok(Glossary { languages, entries })
+    }
+
+    /// Whether terms are translated differently into different languages.
+    pub fn is_language_specific(&self) -> bool {
+        self.languages.is_some()
+    }
+
+    /// A short hash of the glossary's contents (64-bit FNV-1a, as hex), which is the same for the same glossary in every build.
+    pub fn fingerprint(&self) -> String {
+        let contents = serde_json::to_string(self).expect("Glossaries can be serialized");
+        let hash = contents
+            .bytes()
+            .fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+            });
+        format!("{:016x}", hash)
+    }
+
+    /// The terms which have a translation into the language, with their translations.
+    ///
+    /// A column for a language without a region or script, e.g. `pt`, is used for all its variants, e.g. `pt-BR`.
+    pub fn terms(&self, language: &Language) -> BTreeMap<&str, &str> {
+        let column = match &self.languages {
+            None => Some(0),
+            Some(languages) => {
+                let tag = language.to_string();
+                let primary_language = tag.split('-').next().unwrap_or("");
+                languages
+                    .iter()
+                    .position(|l| l.to_string().eq_ignore_ascii_case(&tag))
+                    .or_else(|| {
+                        languages
+                            .iter()
+                            .position(|l| l.to_string().eq_ignore_ascii_case(primary_language))
+                    })
+            }
+        };
+        let Some(column) = column else {
+            return BTreeMap::new();
+        };
+        self.entries
+            .iter()
+            .filter(|(_, translations)| !translations[column].is_empty())
+            .map(|(term, translations)| (term.as_str(), translations[column].as_str()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn language(tag: &str) -> Language {
+        tag.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_glossary() {
+        let glossary = Glossary::parse(
+            "# Product names\nWidgetron,Widgetron\n\"Smart, Fast\",Schnell\n",
+            b',',
+        )
+        .unwrap();
+        assert!(!glossary.is_language_specific());
+        assert_eq!(
+            glossary.terms(&language("de")),
+            BTreeMap::from([("Smart, Fast", "Schnell"), ("Widgetron", "Widgetron")])
+        );
+
+        let glossary = Glossary::parse(
+            "source\tfr\tpt\nsign in\tconnexion\tentrar\nlog\t\tregistro\n",
+            b'\t',
+        )
+        .unwrap();
+        assert!(glossary.is_language_specific());
+        assert_eq!(
+            glossary.terms(&language("FR")),
+            BTreeMap::from([("sign in", "connexion")])
+        );
+        assert_eq!(glossary.terms(&language("pt-BR")).len(), 2);
+        assert!(glossary.terms(&language("de")).is_empty());
+        assert_eq!(glossary.fingerprint().len(), 16);
+        assert_ne!(
+            glossary.fingerprint(),
+            Glossary::parse("source\tfr\nsign in\tconnexion\n", b'\t')
+                .unwrap()
+                .fingerprint()
+        );
+
+        assert_eq!(
+            Glossary::parse("source,fr\nsign in\n", b',')
+                .unwrap_err()
+                .to_string(),
+            "Line 2 should have a term and 1 translation"
+        );
+        assert!(Glossary::parse("source,French\n", b',').is_err());
+    }
+}
@@ -0,0 +1,291 @@
+use crate::cue_text::{self, CueToken, Word};
+use crate::vtt::{Vtt, VttBlock};
+use std::fmt;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Limits on the amount of text shown by each cue.
+#[derive(Debug, Clone, Default)]
+pub struct LayoutLimits {
+    /// Maximum width of a line, in characters. If set, the text of each cue is re-wrapped into balanced lines within this width.
+    pub max_line_chars: Option<usize>,
+    /// Maximum number of lines in a cue. Cues with more are reported, but not changed. If `None`, any number is allowed.
+    pub max_lines: Option<usize>,
+    /// Maximum reading speed, in characters (grapheme clusters, leaving out markup) per second of the cue's duration. Cues which need reading faster are reported, but not changed. If `None`, reading speed isn't checked.
+    pub max_chars_per_second: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LayoutViolation {
+    LineTooLong { chars: usize, max: usize },
+    TooManyLines { lines: usize, max: usize },
+    TooFast { chars_per_second: f64, max: f64 },
+}
+
+impl fmt::Display for LayoutViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LayoutViolation::LineTooLong { chars, max } => write!(
+                f,
+                "line is {} characters long, more than the maximum of {}",
+                chars, max
+            ),
+            LayoutViolation::TooManyLines { lines, max } => {
+                write!(
+                    f,
+                    "cue has {} lines, more than the maximum of {}",
+                    lines, max
+                )
+            }
+            LayoutViolation::TooFast {
+                chars_per_second,
+                max,
+            } => write!(
+                f,
+                "cue needs reading at {:.1} characters per second, faster than the maximum of {}",
+                chars_per_second, max
+            ),
+        }
+    }
+}
+
+/// Re-wrap every cue within the limits, returning the cues (by index) which still break them.
+pub fn apply(vtt: &mut Vtt, limits: &LayoutLimits) -> Vec<(usize, LayoutViolation)> {
+    let mut violations = vec![];
+    for (block_num, block) in vtt.blocks.iter_mut().enumerate() {
+        if let Some(max_line_chars) = limits.max_line_chars {
+            block.text_lines = wrap(&block.text_lines, max_line_chars);
+        }
+        violations.extend(check(block, limits).into_iter().map(|v| (block_num, v)));
+    }
+    violations
+}
+
+/// Check a cue against the limits.
+pub fn check(block: &VttBlock, limits: &LayoutLimits) -> Vec<LayoutViolation> {
+    let mut violations = vec![];
+    let lines: Vec<String> = block
+        .text_lines
+        .iter()
+        .map(|line| {
+            cue_text::plain_text(&cue_text::tokenize(line))
+                .trim()
+                .to_string()
+        })
+        .filter(|line| !line.is_empty())
+        .collect();
+
+    if let Some(max) = limits.max_line_chars {
+        for line in lines.iter() {
+            let chars = cue_text::display_width(line);
+            if chars > max {
+                violations.push(LayoutViolation::LineTooLong { chars, max });
+            }
+        }
+    }
+    if let Some(max) = limits.max_lines {
+        if lines.len() > max {
+            violations.push(LayoutViolation::TooManyLines {
+                lines: lines.len(),
+                max,
+            });
+        }
+    }
+    if let Some(max) = limits.max_chars_per_second {
+        let chars = lines
+            .iter()
+            .map(|l| l.graphemes(true).count())
+            .sum::<usize>();
+        let seconds = (block.end.as_millis() - block.start.as_millis()) as f64 / 1000.0;
+        let chars_per_second = if seconds > 0.0 {
+            chars as f64 / seconds
+        } else {
+            f64::INFINITY
+        };
+        if chars > 0 && chars_per_second > max {
+            violations.push(LayoutViolation::TooFast {
+                chars_per_second,
+                max,
+            });
+        }
+    }
+    violations
+}
+
+/// Wrap the text of a cue into as few lines as will fit within `max_line_chars`, balancing their lengths.
+pub fn wrap(text_lines: &[String], max_line_chars: usize) -> Vec<String> {
+    let words = cue_text::words(&cue_text::tokenize(&text_lines.join("\n")));
+    if words.is_empty() {
+        return text_lines.to_vec();
+    }
+    let widths: Vec<usize> = words
+        .iter()
+        .map(|w| cue_text::display_width(&w.text))
+        .collect();
+
+    let lines = (1..=words.len())
+        .find_map(|num_lines| balanced_breaks(&widths, num_lines, max_line_chars, |_| 0))
+        .unwrap_or_else(|| (1..words.len()).collect());
+
+    let mut wrapped: Vec<Vec<Word>> = vec![];
+    for (word_num, word) in words.into_iter().enumerate() {
+        if word_num == 0 || lines.contains(&word_num) {
+            wrapped.push(vec![]);
+        }
+        wrapped.last_mut().unwrap().push(word);
+    }
+    let text = wrapped
+        .iter()
+        .map(|line| {
+            let tokens: Vec<CueToken> = line
+                .iter()
+                .enumerate()
+                .flat_map(|(n, word)| {
+                    let space = (n > 0).then(|| CueToken::Text(" ".to_string()));
+                    space.into_iter().chain(word.tokens.iter().cloned())
+                })
+                .collect();
+            cue_text::serialize_tokens(&tokens)
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    cue_text::normalise(&text)
+        .split('\n')
+        .map(String::from)
+        .collect()
+}
+
+/// Find the indexes of the words which start each line when splitting into `num_lines` lines of as even a width as possible.
+///
+/// `break_cost` gives an additional cost for starting a line at each word, to favour some places to break over others.
+/// Returns None if the words can't be split into that many lines within `max_width` (a single word which is too wide is allowed a line of its own).
+pub(crate) fn balanced_breaks<F: Fn(usize) -> usize>(
+    widths: &[usize],
+    num_lines: usize,
+    max_width: usize,
+    break_cost: F,
+) -> Option<Vec<usize>> {
+    let line_width = |from: usize, to: usize| -> usize {
+        widths[from..to].iter().sum::<usize>() + (to - from - 1)
+    };
+    let fits = |from: usize, to: usize| to - from == 1 || line_width(from, to) <= max_width;
+
+    // cost[k][i] is the lowest sum of squared line widths for words 0..i in k lines
+    let n = widths.len();
+    let mut cost = vec![vec![None; n + 1]; num_lines + 1];
+    let mut previous_break = vec![vec![0; n + 1]; num_lines + 1];
+    cost[0][0] = Some(0);
+    for k in 1..=num_lines {
+        for i in 1..=n {
+            for j in (k - 1)..i {
+                if let Some(c) = cost[k - 1][j] {
+                    if fits(j, i) {
+                        let c = c + line_width(j, i).pow(2) + if j > 0 { break_cost(j) } else { 0 };
+                        if cost[k][i].is_none_or(|best| c < best) {
+                            cost[k][i] = Some(c);
+                            previous_break[k][i] = j;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    cost[num_lines][n]?;
+
+    let mut breaks = vec![];
+    let mut i = n;
+    for k in (1..=num_lines).rev() {
+        i = previous_break[k][i];
+        if i > 0 {
+            breaks.push(i);
+        }
+    }
+    breaks.reverse();
+    Some(breaks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &[&str]) -> Vec<String> {
+        text.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn test_wrap_balances_lines() {
+        assert_eq!(
+            wrap(
+                &lines(&[
+                    "This is a rather long translated line which will not fit",
+                    ""
+                ]),
+                42
+            ),
+            lines(&[
+                "This is a rather long translated",
+                "line which will not fit"
+            ])
+        );
+        assert_eq!(wrap(&lines(&["Short", "text"]), 42), lines(&["Short text"]));
+        assert_eq!(
+            wrap(&lines(&["one two three four five six"]), 9),
+            lines(&["one two", "three", "four", "five six"])
+        );
+    }
+
+    #[test]
+    fn test_wrap_keeps_markup() {
+        assert_eq!(
+            wrap(
+                &lines(&["<v Fred Bloggs>This is <i>a rather long</i>", "line</v>"]),
+                20
+            ),
+            lines(&["<v Fred Bloggs>This is <i>a", "rather long</i> line</v>"])
+        );
+    }
+
+    #[test]
+    fn test_check_limits() {
+        let vtt = Vtt::parse_str(
+            "WEBVTT\n\n00:01.000 --> 00:02.000\nA line which is far too long for the cue\nand another\nand another\n",
+        )
+        .unwrap();
+        let limits = LayoutLimits {
+            max_line_chars: Some(32),
+            max_lines: Some(2),
+            max_chars_per_second: Some(20.0),
+        };
+        assert_eq!(
+            check(&vtt.blocks[0], &limits),
+            vec![
+                LayoutViolation::LineTooLong { chars: 40, max: 32 },
+                LayoutViolation::TooManyLines { lines: 3, max: 2 },
+                LayoutViolation::TooFast {
+                    chars_per_second: 62.0,
+                    max: 20.0
+                },
+            ]
+        );
+
+        // Re-wrapping fixes the line lengths and number of lines, but not the reading speed
+        let mut wrapped = vtt.clone();
+        let violations = apply(&mut wrapped, &limits);
+        assert_eq!(
+            wrapped.blocks[0].text_lines,
+            lines(&[
+                "A line which is far too long for",
+                "the cue and another and another"
+            ])
+        );
+        assert_eq!(
+            violations,
+            vec![(
+                0,
+                LayoutViolation::TooFast {
+                    chars_per_second: 63.0,
+                    max: 20.0
+                }
+            )]
+        );
+    }
+}
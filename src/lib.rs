@@ -1,50 +1,277 @@
-use crate::translate::{Language, TranslationClient};
+use crate::cue_text::Word;
+use crate::cue_text::{CueTag, CueToken};
+use crate::glossary::Glossary;
+use crate::layout::LayoutLimits;
+use crate::memory::TranslationMemory;
+use crate::protect::{ProtectBuiltin, Protector};
+use crate::retime::RetimeOptions;
+use crate::retry::RetryPolicy;
+use crate::segment::SentenceSegmenter;
+use crate::translate::{
+    billed_chars, AzureAuth, AzureCloud, CachingTranslator, DeepLClient, GoogleClient, Language,
+    LanguageInfo, LibreTranslateClient, MeteredTranslator, OpenAiClient, PseudoTranslator,
+    Translation, TranslationClient, Translator, Usage,
+};
 use crate::vtt::Vtt;
-use anyhow::{Context, Result};
-use clap::Parser;
+use anyhow::{bail, Context, Result};
+use clap::{Parser, ValueEnum};
 use regex::Regex;
+use std::collections::BTreeMap;
 use std::ffi::OsStr;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+pub mod cue_text;
+pub mod glossary;
+pub mod layout;
+pub mod memory;
+pub mod protect;
+pub mod retime;
+pub mod retry;
+pub mod segment;
 pub mod translate;
 pub mod vtt;
 
+/// The machine translation APIs which can be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    Azure,
+    Deepl,
+    Google,
+    Libretranslate,
+    Openai,
+    Pseudo,
+}
+
+/// The ways of authenticating with an Azure Translation resource.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AzureAuthMethod {
+    Key,
+    BearerToken,
+    ClientCredentials,
+    IssueToken,
+}
+
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
     /// The VTT file to translate.
-    #[arg(long, short = 'f')]
-    source_vtt_file: PathBuf,
+    #[arg(
+        long,
+        short = 'f',
+        required_unless_present_any = ["list_languages", "import_tmx", "export_tmx", "prune_translation_cache"]
+    )]
+    source_vtt_file: Option<PathBuf>,
 
-    /// The output translated VTT file to write (whichwill be overwritten). Defaults to an auto-generated filename based on source_vtt_file and target_language.
+    /// The output translated VTT file to write (whichwill be overwritten). Defaults to an auto-generated filename based on source_vtt_file and target_language. Can only be given when translating to a single language.
     #[arg(long)]
     target_vtt_file: Option<PathBuf>,
 
-    /// Language the source VTT file is in. If not specified then we attempt to auto-detect it.
+    /// Language the source VTT file is in, as a BCP-47 language tag, which also selects the rules for finding the ends of sentences. If not specified then it's detected from the first 500 characters of the file, which most translation APIs bill for.
     #[arg(long)]
     source_language: Option<Language>,
 
-    /// Language to translate the VTT file to.
-    #[arg(long, short = 'l', default_value_t = Language::Fa)]
-    target_language: Language,
+    /// Languages to translate the VTT file to, as BCP-47 language tags separated by commas (or by repeating the option). A translated VTT file is written for each. See --list-languages for the supported languages.
+    #[arg(long, short = 'l', value_delimiter = ',', num_args = 1.., default_value = "fa")]
+    target_language: Vec<Language>,
 
-    /// Key for the Azure Translation resource.
+    /// List the languages supported by the translation API, and exit.
+    #[arg(long)]
+    list_languages: bool,
+
+    /// The machine translation API to use: Azure Text Translation, DeepL, Google Cloud Translation (Basic edition), a self-hosted LibreTranslate server, a language model behind an OpenAI-compatible chat completions API, or offline pseudo-localisation (into en-XA or the right-to-left ar-XB) to preview the layout of translations.
+    #[arg(long, value_enum, default_value_t = Backend::Azure)]
+    backend: Backend,
+
+    /// Key for the Azure Translation resource, required by the azure backend when authenticating with the key or exchanging it with --azure-auth issue-token.
     #[arg(long, env = "AZURE_TRANSLATION_RESOURCE_KEY")]
-    azure_resource_key: String,
+    azure_resource_key: Option<String>,
+
+    /// How to authenticate with the Azure Translation resource: with its key, a Microsoft Entra ID access token given by --azure-bearer-token, Microsoft Entra ID access tokens for a service principal (see --azure-tenant-id, --azure-client-id and --azure-client-secret), or access tokens exchanged for the key at the issueToken endpoint. Access tokens are refreshed before they expire.
+    #[arg(long, value_enum, default_value_t = AzureAuthMethod::Key)]
+    azure_auth: AzureAuthMethod,
+
+    /// Microsoft Entra ID access token for the Azure Translation resource, with --azure-auth bearer-token.
+    #[arg(long, env = "AZURE_TRANSLATION_BEARER_TOKEN")]
+    azure_bearer_token: Option<String>,
 
-    /// Azure region the Translation resource is running in.
+    /// Microsoft Entra ID tenant of the service principal, with --azure-auth client-credentials.
+    #[arg(long, env = "AZURE_TENANT_ID")]
+    azure_tenant_id: Option<String>,
+
+    /// Application (client) ID of the service principal, with --azure-auth client-credentials.
+    #[arg(long, env = "AZURE_CLIENT_ID")]
+    azure_client_id: Option<String>,
+
+    /// Client secret of the service principal, with --azure-auth client-credentials.
+    #[arg(long, env = "AZURE_CLIENT_SECRET")]
+    azure_client_secret: Option<String>,
+
+    /// Endpoint to fetch access tokens from, overriding the tenant's token endpoint (with --azure-auth client-credentials) or the region's issueToken endpoint (with --azure-auth issue-token).
+    #[arg(long, env = "AZURE_TOKEN_ENDPOINT")]
+    azure_token_endpoint: Option<String>,
+
+    /// Resource ID of the Azure Translation resource, which regional resources need when authenticating with Microsoft Entra ID.
+    #[arg(long, env = "AZURE_TRANSLATION_RESOURCE_ID")]
+    azure_resource_id: Option<String>,
+
+    /// Azure region the Translation resource is running in, required by the azure backend unless --no-azure-region-header is given.
     #[arg(long, env = "AZURE_TRANSLATION_RESOURCE_REGION")]
-    azure_resource_region: String,
+    azure_resource_region: Option<String>,
+
+    /// Don't send the region of the Azure Translation resource, which single-service global resources and custom domains don't need.
+    #[arg(long)]
+    no_azure_region_header: bool,
+
+    /// The Azure cloud the Translation resource is in, which selects its global endpoint, and the endpoints and scope for access tokens.
+    #[arg(long, value_enum, env = "AZURE_CLOUD", default_value_t = AzureCloud::Public)]
+    azure_cloud: AzureCloud,
+
+    /// Endpoint of the Azure Translation resource, overriding the cloud's global endpoint, e.g. a custom domain such as https://<name>.cognitiveservices.azure.com/translator/text/v3.0 or a private endpoint.
+    #[arg(long, env = "AZURE_TRANSLATION_ENDPOINT")]
+    azure_endpoint: Option<String>,
+
+    /// Category ID of a Custom Translator model to translate with, with the azure backend.
+    #[arg(long, env = "AZURE_TRANSLATOR_CATEGORY")]
+    azure_category: Option<String>,
+
+    /// CSV (or TSV) file of terms and their translations, which the azure backend translates as given. Each line is a term and its translation, or a header line of "source" followed by language tags gives a column of translations for each target language.
+    #[arg(long)]
+    glossary: Option<PathBuf>,
+
+    /// Version of the Azure Text Translation API to use.
+    #[arg(long, env = "AZURE_TRANSLATION_API_VERSION", default_value = "3.0")]
+    azure_api_version: String,
+
+    /// Authentication key for the DeepL API, required by the deepl backend.
+    #[arg(long, env = "DEEPL_AUTH_KEY")]
+    deepl_auth_key: Option<String>,
+
+    /// API key for Google Cloud Translation, required by the google backend.
+    #[arg(long, env = "GOOGLE_TRANSLATE_API_KEY")]
+    google_api_key: Option<String>,
+
+    /// Base URL of the LibreTranslate server, e.g. http://localhost:5000, required by the libretranslate backend.
+    #[arg(long, env = "LIBRETRANSLATE_URL")]
+    libretranslate_url: Option<String>,
+
+    /// API key for the LibreTranslate server, if it requires one.
+    #[arg(long, env = "LIBRETRANSLATE_API_KEY")]
+    libretranslate_api_key: Option<String>,
+
+    /// Name of the language model to use, required by the openai backend.
+    #[arg(long, env = "OPENAI_MODEL")]
+    openai_model: Option<String>,
+
+    /// Base URL of the OpenAI-compatible API, e.g. http://localhost:8080/v1 for a local llama.cpp server. Defaults to OpenAI's API.
+    #[arg(long, env = "OPENAI_BASE_URL")]
+    openai_url: Option<String>,
+
+    /// API key for the OpenAI-compatible API, if it requires one.
+    #[arg(long, env = "OPENAI_API_KEY")]
+    openai_api_key: Option<String>,
+
+    /// Number of sentences before and after those being translated to send to the language model as context, with the openai backend.
+    #[arg(long, default_value_t = 3)]
+    context_sentences: usize,
+
+    /// Regular expression matching text which must not be translated, such as names. Can be repeated.
+    #[arg(long)]
+    protect: Vec<String>,
+
+    /// Built-in detectors of text which must not be translated, separated by commas.
+    #[arg(long, value_enum, value_delimiter = ',')]
+    protect_builtin: Vec<ProtectBuiltin>,
+
+    /// SQLite database of translations to reuse, so sentences which have been translated before (with the same backend and options) aren't paid for again. It's created if need be.
+    #[arg(long)]
+    translation_cache: Option<PathBuf>,
+
+    /// Import the translations in a TMX file into the translation cache, and exit. Translations from other tools are used with any backend.
+    #[arg(long, requires = "translation_cache")]
+    import_tmx: Option<PathBuf>,
+
+    /// Export the translation cache to a TMX file, and exit.
+    #[arg(long, requires = "translation_cache")]
+    export_tmx: Option<PathBuf>,
+
+    /// Remove translations which haven't been used for this many days (up to 36500) from the translation cache, and exit.
+    #[arg(long, requires = "translation_cache", value_parser = clap::value_parser!(u64).range(..=36_500))]
+    prune_translation_cache: Option<u64>,
+
+    /// Count the characters which would be sent to the translation API for each target language, leaving out translations in the translation cache (if the source language is given), and estimate the cost, without calling the API or writing any files.
+    #[arg(long, conflicts_with_all = ["list_languages", "import_tmx", "prune_translation_cache", "export_tmx"])]
+    dry_run: bool,
+
+    /// Price per million characters sent to the translation API, for estimating the cost. Defaults to Azure's pay-as-you-go price in US dollars.
+    #[arg(long, default_value_t = 10.0)]
+    price_per_million_chars: f64,
+
+    /// Stop before sending more than this many characters to the translation API in total, counting them once for each target language, to keep within a budget.
+    #[arg(long)]
+    max_billed_chars: Option<usize>,
+
+    /// Maximum number of translation requests to send at once, when the file is too long to translate in a single request.
+    #[arg(long, default_value_t = 1)]
+    max_concurrent_requests: usize,
+
+    /// Maximum number of attempts at each translation request. Requests which are throttled or fail with a server error are retried with exponential backoff.
+    #[arg(long, default_value_t = 5)]
+    max_attempts: u32,
+
+    /// Maximum number of characters per line. If specified then the text of each translated cue is re-wrapped into balanced lines of at most this length.
+    #[arg(long)]
+    max_line_chars: Option<usize>,
+
+    /// Maximum number of lines per cue. Cues with more lines are reported.
+    #[arg(long)]
+    max_lines: Option<usize>,
+
+    /// Maximum reading speed, in characters per second. Cues which need reading faster are reported.
+    #[arg(long)]
+    max_chars_per_second: Option<f64>,
+
+    /// Re-time the translated cues: split cues with too much text to fit on screen, merge cues with almost no text into their neighbours, and lengthen cues to give enough time to read them. The cues are given new sequential ids.
+    #[arg(long)]
+    retime: bool,
+
+    /// Minimum duration of a cue in milliseconds, when re-timing.
+    #[arg(long, default_value_t = 1000)]
+    min_cue_duration_ms: u64,
+
+    /// Minimum gap between cues in milliseconds, when re-timing.
+    #[arg(long, default_value_t = 80)]
+    min_cue_gap_ms: u64,
+
+    /// Cues with fewer characters than this are merged into a neighbouring cue, when re-timing.
+    #[arg(long, default_value_t = 5)]
+    min_cue_chars: usize,
 }
 
+/// How much of the start of the file to use to detect its language, which is billed like translation by most APIs.
+static DETECTION_SAMPLE_CHARS: usize = 500;
+
 #[derive(Debug, Clone)]
 struct ChunkDesc {
     block_num: usize,
     line_num: usize,
     chunk_len: usize,
+    /// The range of characters in the sentence's text which came from this chunk.
+    text_range: Range<usize>,
+    /// The `<v>` tag for the speaker of this chunk, if any.
+    voice: Option<CueTag>,
 }
 
-type Sentence = (Vec<ChunkDesc>, String);
+#[derive(Debug, Clone, Default)]
+struct Sentence {
+    chunk_descs: Vec<ChunkDesc>,
+    /// The sentence as HTML, with cue markup replaced by numbered spans (see `cue_text::to_html`).
+    text: String,
+    /// The cue tags and timestamps referenced by the spans in `text`.
+    markup: Vec<CueToken>,
+}
 
 impl From<crate::translate::Direction> for crate::vtt::Direction {
     fn from(value: crate::translate::Direction) -> Self {
@@ -55,97 +282,294 @@ impl From<crate::translate::Direction> for crate::vtt::Direction {
     }
 }
 
-fn recontruct_sentences(vtt: &Vtt) -> Vec<Sentence> {
+/// Byte offsets in the plain text of `tokens` at which a new speaker's voice tag starts.
+fn voice_offsets(tokens: &[CueToken]) -> Vec<usize> {
+    let mut offsets = vec![];
+    let mut offset = 0;
+    for token in tokens {
+        match token {
+            CueToken::Text(text) => offset += text.len(),
+            CueToken::StartTag(tag) if tag.name == "v" && offset > 0 => offsets.push(offset),
+            _ => {}
+        }
+    }
+    offsets
+}
+
+/// The voice tag which applies to the text of `chunk`, given the tags already open at its start.
+fn chunk_voice(open_tags: &[CueTag], chunk: &[CueToken]) -> Option<CueTag> {
+    let mut open_tags = open_tags.to_vec();
+    let leading_tags = chunk
+        .iter()
+        .position(|token| matches!(token, CueToken::Text(_)))
+        .unwrap_or(chunk.len());
+    cue_text::update_open_tags(&mut open_tags, &chunk[..leading_tags]);
+    open_tags.into_iter().rev().find(|tag| tag.name == "v")
+}
+
+fn recontruct_sentences(vtt: &Vtt, segmenter: &SentenceSegmenter) -> Vec<Sentence> {
     let mut all_sentences: Vec<Sentence> = vec![];
-    let mut this_sentence = "".to_string();
-    let mut this_sentence_chunk_descs: Vec<ChunkDesc> = vec![];
+    let mut this_sentence = Sentence::default();
 
     for (block_num, block) in vtt.blocks.iter().enumerate() {
-        for (line_num, text_line) in block.text_lines.clone().into_iter().enumerate() {
-            let mut chunks = text_line.trim().split('.').peekable();
-            while let Some(chunk) = chunks.next() {
-                // A trailing fullstop results in an empty chunk, which we can ignore
-                if !chunk.is_empty() {
-                    let chunk = chunk.trim();
-                    let chunk_desc = ChunkDesc {
+        // Tags can span lines within a cue, so track which are open at the start of each chunk
+        let mut open_tags: Vec<CueTag> = vec![];
+
+        for (line_num, text_line) in block.text_lines.iter().enumerate() {
+            let tokens = cue_text::tokenize(text_line.trim());
+            let sentence_ends = segmenter.sentence_ends(&cue_text::plain_text(&tokens));
+
+            // Split the line into chunks at the end of each sentence and wherever the speaker changes
+            let mut split_offsets = sentence_ends.clone();
+            split_offsets.extend(voice_offsets(&tokens));
+            split_offsets.sort();
+            split_offsets.dedup();
+            let chunks = cue_text::split_at(&tokens, &split_offsets);
+
+            for (chunk_num, mut chunk) in chunks.into_iter().enumerate() {
+                cue_text::trim(&mut chunk);
+                let chunk_text = cue_text::plain_text(&chunk);
+                let voice = chunk_voice(&open_tags, &chunk);
+
+                // Make the chunk standalone by re-opening and closing any tags which span it
+                let mut chunk_tokens: Vec<CueToken> =
+                    open_tags.iter().cloned().map(CueToken::StartTag).collect();
+                chunk_tokens.extend(chunk.iter().cloned());
+                cue_text::update_open_tags(&mut open_tags, &chunk);
+                chunk_tokens.extend(
+                    open_tags
+                        .iter()
+                        .rev()
+                        .map(|tag| CueToken::EndTag(tag.name.clone())),
+                );
+
+                // Voice tags are re-added to each cue after translation, so that the speaker's name isn't translated
+                chunk_tokens.retain(|token| match token {
+                    CueToken::StartTag(tag) => tag.name != "v",
+                    CueToken::EndTag(name) => name != "v",
+                    _ => true,
+                });
+
+                // A chunk with no text (e.g. a trailing fullstop) can be ignored
+                if !chunk_text.is_empty() {
+                    // Sentences never continue across a change of speaker
+                    if this_sentence
+                        .chunk_descs
+                        .last()
+                        .is_some_and(|cd| cd.voice != voice)
+                    {
+                        all_sentences.push(std::mem::take(&mut this_sentence));
+                    }
+
+                    if !this_sentence.text.is_empty() {
+                        this_sentence.text.push(' ');
+                    }
+                    let chunk_start = this_sentence.text.chars().count();
+                    this_sentence
+                        .text
+                        .push_str(&cue_text::to_html(&chunk_tokens, &mut this_sentence.markup));
+
+                    this_sentence.chunk_descs.push(ChunkDesc {
                         block_num,
                         line_num,
-                        chunk_len: chunk.len(),
-                    };
-
-                    this_sentence.push_str(chunk);
-                    this_sentence_chunk_descs.push(chunk_desc);
+                        chunk_len: cue_text::display_width(&chunk_text),
+                        text_range: chunk_start..this_sentence.text.chars().count(),
+                        voice,
+                    });
 
-                    if chunks.peek().is_some() {
+                    if split_offsets
+                        .get(chunk_num)
+                        .is_some_and(|offset| sentence_ends.contains(offset))
+                    {
                         // This is the end of a sentence
-                        this_sentence.push('.');
-                        all_sentences.push((this_sentence_chunk_descs, this_sentence));
-                        this_sentence = "".to_string();
-                        this_sentence_chunk_descs = vec![];
-                    } else {
-                        // Add a trailing space to unfinished sentence
-                        this_sentence.push(' ');
+                        all_sentences.push(std::mem::take(&mut this_sentence));
                     }
                 }
             }
         }
     }
+    if !this_sentence.chunk_descs.is_empty() {
+        all_sentences.push(this_sentence);
+    }
     all_sentences
 }
 
-fn update_vtt(vtt: &mut Vtt, sentences: &Vec<Sentence>) {
+/// Assign each word to a chunk, in proportion to the lengths of the chunks in the original text.
+fn proportional_word_chunks(sentence: &Sentence, words: &[Word]) -> Vec<usize> {
+    let new_text_len = words
+        .iter()
+        .map(|w| cue_text::display_width(&w.text) + 1)
+        .sum::<usize>();
+    let mut words = words.iter().peekable();
+    let mut word_chunks = vec![];
+
+    // Calculate the total width of all chunks in the original text
+    let total_chunks_len = sentence
+        .chunk_descs
+        .iter()
+        .map(|cd| cd.chunk_len)
+        .sum::<usize>()
+        .max(1);
+
+    // Iterate through the chunks and add equivalent sized portions of the new text to them
+    let mut chunk_descs = sentence.chunk_descs.iter().enumerate().peekable();
+    while let Some((chunk_num, chunk_desc)) = chunk_descs.next() {
+        // Calculate the desired width of this chunk
+        let new_chunk_size = chunk_desc.chunk_len * new_text_len / total_chunks_len;
+
+        // Add words to this chunk until it is close to or greater than the desired length, or there are no later chunks to add the remaining words to
+        let mut new_chunk_len = 0;
+        while (new_chunk_len == 0
+            || new_chunk_len + 3 <= new_chunk_size
+            || chunk_descs.peek().is_none())
+            && words.peek().is_some()
+        {
+            new_chunk_len += cue_text::display_width(&words.next().unwrap().text) + 1;
+            word_chunks.push(chunk_num);
+        }
+    }
+    word_chunks
+}
+
+/// Assign each word to the chunk containing the source words it is aligned with, keeping the words in order.
+///
+/// `text_offsets` gives the character index in the translated text of each character of the words' plain text (see `cue_text::from_html_with_offsets`).
+/// Returns None if there is no alignment information for any of the words.
+fn aligned_word_chunks(
+    sentence: &Sentence,
+    translation: &Translation,
+    words: &[Word],
+    plain_text: &str,
+    text_offsets: &[usize],
+) -> Option<Vec<usize>> {
+    let alignment = translation.alignment.as_ref()?;
+
+    // Find the range of characters in the translation that each word came from
+    let mut chars = plain_text.chars().enumerate().peekable();
+    let mut aligned_chunks: Vec<Option<usize>> = vec![];
+    for word in words {
+        while chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+        let first = chars.peek()?.0;
+        let last = first + word.text.chars().count() - 1;
+        for _ in first..=last {
+            chars.next();
+        }
+        let target = text_offsets[first]..text_offsets[last] + 1;
+
+        // Use the earliest chunk containing any of the source text aligned with this word
+        aligned_chunks.push(
+            alignment
+                .iter()
+                .filter(|span| span.target.start < target.end && target.start < span.target.end)
+                .filter_map(|span| {
+                    sentence
+                        .chunk_descs
+                        .iter()
+                        .rposition(|cd| cd.text_range.start <= span.source.start)
+                })
+                .min(),
+        );
+    }
+    if aligned_chunks.iter().all(Option::is_none) {
+        return None;
+    }
+    Some(monotonic_fit(&aligned_chunks, sentence.chunk_descs.len()))
+}
+
+/// Find the non-decreasing sequence of chunk numbers closest to the (optional) preferred chunk for each word.
+fn monotonic_fit(preferred: &[Option<usize>], num_chunks: usize) -> Vec<usize> {
+    // cost[i][c] is the lowest total distance for words 0..=i with word i in chunk c
+    let mut cost: Vec<Vec<usize>> = vec![];
+    for (i, preferred_chunk) in preferred.iter().enumerate() {
+        let mut best_previous = usize::MAX;
+        let row = (0..num_chunks)
+            .map(|c| {
+                let previous = if i == 0 {
+                    0
+                } else {
+                    best_previous = best_previous.min(cost[i - 1][c]);
+                    best_previous
+                };
+                previous + preferred_chunk.map_or(0, |p| p.abs_diff(c))
+            })
+            .collect();
+        cost.push(row);
+    }
+
+    // Work backwards, picking the cheapest chunk no later than the following word's
+    let mut chunks = vec![0; preferred.len()];
+    let mut limit = num_chunks;
+    for i in (0..preferred.len()).rev() {
+        let chunk = (0..limit).min_by_key(|c| cost[i][*c]).unwrap_or(0);
+        chunks[i] = chunk;
+        limit = chunk + 1;
+    }
+    chunks
+}
+
+fn update_vtt(vtt: &mut Vtt, sentences: &[Sentence], translations: &[Translation]) {
     // Initialize the vtt block text lines with empty strings (deleting any existing ones)
     vtt.blocks.iter_mut().for_each(|vb| {
         vb.text_lines = vec!["".to_string(); vb.text_lines.len()];
     });
 
     // Iterate through all the sentences and update the vtt blocks with the new text
-    for sentence in sentences {
-        let new_text = sentence.1.clone();
-        let mut new_text_words = new_text.split(' ');
-        let mut next_word = new_text_words.next();
-
-        // Calculate the total length of all chunks in the original text
-        let mut total_chunks_len = 0;
-        sentence
-            .0
-            .clone()
-            .into_iter()
-            .for_each(|cd| total_chunks_len += cd.chunk_len);
-
-        // Iterate through the chunks and add equivalent sized portions of the new text to the vtt blocks
-        let mut chunk_descs = sentence.0.clone().into_iter().peekable();
-        while let Some(chunk_desc) = chunk_descs.next() {
-            // Calculate the desired number of characters in this chunk
-            let new_chunk_size = chunk_desc.chunk_len * new_text.len() / total_chunks_len;
-
-            // Add words to this chunk until it is close to or greater than the desired length, or there are no later chunks to add the remaining words to
-            let mut new_chunk_text = "".to_string();
-            while (new_chunk_text.is_empty()
-                || new_chunk_text.len() + 3 <= new_chunk_size
-                || chunk_descs.peek().is_none())
-                && next_word.is_some()
-            {
-                if !next_word.unwrap().is_empty() {
-                    new_chunk_text += next_word.unwrap();
-                    new_chunk_text += " ";
-                }
-                next_word = new_text_words.next();
+    for (sentence, translation) in sentences.iter().zip(translations) {
+        let (tokens, text_offsets) =
+            cue_text::from_html_with_offsets(&translation.text, &sentence.markup);
+        let new_words = cue_text::words(&tokens);
+
+        // Place each word in the chunk which its source text was in if we know it, otherwise split the text proportionally
+        let word_chunks = aligned_word_chunks(
+            sentence,
+            translation,
+            &new_words,
+            &cue_text::plain_text(&tokens),
+            &text_offsets,
+        )
+        .unwrap_or_else(|| proportional_word_chunks(sentence, &new_words));
+
+        let mut new_chunks: Vec<Vec<CueToken>> = vec![vec![]; sentence.chunk_descs.len()];
+        for (word, chunk_num) in new_words.into_iter().zip(word_chunks) {
+            let new_chunk = &mut new_chunks[chunk_num];
+            if !new_chunk.is_empty() {
+                new_chunk.push(CueToken::Text(" ".to_string()));
             }
+            new_chunk.extend(word.tokens);
+        }
 
-            // Add this chunk to the vtt block, including a preceeding space if necessary
-            if !vtt.blocks[chunk_desc.block_num].text_lines[chunk_desc.line_num].is_empty() {
-                vtt.blocks[chunk_desc.block_num].text_lines[chunk_desc.line_num] += " ";
+        // Add the chunks to the vtt blocks with their speaker's voice tag, including a preceeding space if necessary
+        let new_chunks = cue_text::serialize_balanced(&new_chunks);
+        for (chunk_desc, mut new_chunk_text) in sentence.chunk_descs.iter().zip(new_chunks) {
+            if let Some(voice) = &chunk_desc.voice {
+                if !new_chunk_text.is_empty() {
+                    new_chunk_text =
+                        cue_text::serialize_tokens(&[CueToken::StartTag(voice.clone())])
+                            + &new_chunk_text
+                            + "</v>";
+                }
+            }
+            let line = &mut vtt.blocks[chunk_desc.block_num].text_lines[chunk_desc.line_num];
+            if !line.is_empty() && !new_chunk_text.is_empty() {
+                *line += " ";
             }
-            vtt.blocks[chunk_desc.block_num].text_lines[chunk_desc.line_num] += &new_chunk_text;
+            *line += &new_chunk_text;
         }
     }
+
+    // Tidy up the markup now that the cues contain whole chunks
+    vtt.blocks.iter_mut().for_each(|vb| {
+        vb.text_lines = cue_text::normalise(&vb.text_lines.join("\n"))
+            .split('\n')
+            .map(String::from)
+            .collect();
+    });
 }
 
 fn default_target_filename(
     source_filename: &Path,
-    source_language: Language,
-    target_language: Language,
+    source_language: &Language,
+    target_language: &Language,
 ) -> PathBuf {
     let directory = source_filename.parent().unwrap_or(Path::new(""));
     let stem = source_filename
@@ -166,7 +590,9 @@ fn default_target_filename(
     let filename_re: Regex = Regex::new(&format!(
         "^{}{}$",
         r"(?P<prefix>.+?)",
-        r"(?P<language>-(?i)".to_owned() + &source_language.to_string() + "(-[A-Za-z]{2})?)?"
+        r"(?P<language>-(?i)".to_owned()
+            + &regex::escape(&source_language.to_string())
+            + "(-[A-Za-z]{2})?)?"
     ))
     .unwrap();
 
@@ -189,51 +615,535 @@ fn default_target_filename(
     directory.join(target_filename)
 }
 
+/// Print a table of the supported languages.
+fn list_languages(languages: &BTreeMap<String, LanguageInfo>) {
+    let pad = |text: &str, width: usize| {
+        format!(
+            "{}{}",
+            text,
+            " ".repeat(width.saturating_sub(cue_text::display_width(text)))
+        )
+    };
+    let code_width = languages.keys().map(|c| c.len()).max().unwrap_or(0);
+    let name_width = languages
+        .values()
+        .map(|l| cue_text::display_width(&l.name))
+        .max()
+        .unwrap_or(0);
+    let native_name_width = languages
+        .values()
+        .map(|l| cue_text::display_width(&l.native_name))
+        .max()
+        .unwrap_or(0);
+    for (code, language) in languages {
+        println!(
+            "{}  {}  {}  {:?}",
+            pad(code, code_width),
+            pad(&language.name, name_width),
+            pad(&language.native_name, native_name_width),
+            language.direction
+        );
+    }
+}
+
+/// How to authenticate with the Azure Translation resource, from the options for the chosen method.
+fn azure_auth(args: &Cli, region: &str) -> Result<AzureAuth> {
+    let required = |value: &Option<String>, option: &str| {
+        value.clone().with_context(|| {
+            format!(
+                "{} is required for the azure backend with --azure-auth {}",
+                option,
+                args.azure_auth.to_possible_value().unwrap().get_name()
+            )
+        })
+    };
+    Ok(match args.azure_auth {
+        AzureAuthMethod::Key => {
+            AzureAuth::Key(required(&args.azure_resource_key, "--azure-resource-key")?)
+        }
+        AzureAuthMethod::BearerToken => {
+            AzureAuth::BearerToken(required(&args.azure_bearer_token, "--azure-bearer-token")?)
+        }
+        AzureAuthMethod::ClientCredentials => AzureAuth::ClientCredentials {
+            token_endpoint: match &args.azure_token_endpoint {
+                Some(token_endpoint) => token_endpoint.clone(),
+                None => AzureAuth::tenant_token_endpoint(
+                    args.azure_cloud,
+                    &required(&args.azure_tenant_id, "--azure-tenant-id")?,
+                ),
+            },
+            scope: args.azure_cloud.scope().to_string(),
+            client_id: required(&args.azure_client_id, "--azure-client-id")?,
+            client_secret: required(&args.azure_client_secret, "--azure-client-secret")?,
+        },
+        AzureAuthMethod::IssueToken => AzureAuth::IssueToken {
+            token_endpoint: args
+                .azure_token_endpoint
+                .clone()
+                .unwrap_or_else(|| AzureAuth::issue_token_endpoint(args.azure_cloud, region)),
+            key: required(&args.azure_resource_key, "--azure-resource-key")?,
+        },
+    })
+}
+
+/// Create a client for the translation API selected on the command line.
+fn create_translator(args: &Cli) -> Result<Box<dyn Translator>> {
+    let retry_policy = RetryPolicy {
+        max_attempts: args.max_attempts,
+        ..RetryPolicy::default()
+    };
+    if args.glossary.is_some() && args.backend != Backend::Azure {
+        bail!("--glossary is only supported by the azure backend");
+    }
+    // A dry run doesn't call the API, so doesn't need credentials
+    let required = |value: &Option<String>, message: &'static str| {
+        if args.dry_run {
+            return Ok(value.clone().unwrap_or_default());
+        }
+        value.clone().context(message)
+    };
+    let translator: Box<dyn Translator> = match args.backend {
+        Backend::Azure => {
+            // Listing the languages doesn't need authentication, or a region
+            let region = if args.list_languages || args.no_azure_region_header || args.dry_run {
+                args.azure_resource_region.clone().unwrap_or_default()
+            } else {
+                args.azure_resource_region
+                    .clone()
+                    .context("--azure-resource-region is required for the azure backend")?
+            };
+            let auth = if args.list_languages || args.dry_run {
+                AzureAuth::Key(String::new())
+            } else {
+                azure_auth(args, &region)?
+            };
+            let mut client = TranslationClient::new(auth, region)
+                .with_cloud(args.azure_cloud)
+                .with_api_version(&args.azure_api_version)
+                .with_region_header(!args.no_azure_region_header)
+                .with_max_concurrent_requests(args.max_concurrent_requests)
+                .with_retry_policy(retry_policy);
+            if let Some(azure_endpoint) = &args.azure_endpoint {
+                client = client.with_endpoint(azure_endpoint);
+            }
+            if let Some(azure_resource_id) = &args.azure_resource_id {
+                client = client.with_resource_id(azure_resource_id);
+            }
+            if let Some(azure_category) = &args.azure_category {
+                client = client.with_category(azure_category);
+            }
+            if let Some(glossary) = &args.glossary {
+                client = client.with_glossary(Glossary::load(glossary)?);
+            }
+            Box::new(client)
+        }
+        Backend::Deepl => Box::new(
+            DeepLClient::new(required(
+                &args.deepl_auth_key,
+                "--deepl-auth-key is required for the deepl backend",
+            )?)
+            .with_max_concurrent_requests(args.max_concurrent_requests)
+            .with_retry_policy(retry_policy),
+        ),
+        Backend::Google => Box::new(
+            GoogleClient::new(required(
+                &args.google_api_key,
+                "--google-api-key is required for the google backend",
+            )?)
+            .with_max_concurrent_requests(args.max_concurrent_requests)
+            .with_retry_policy(retry_policy),
+        ),
+        Backend::Libretranslate => Box::new(
+            LibreTranslateClient::new(
+                &required(
+                    &args.libretranslate_url,
+                    "--libretranslate-url is required for the libretranslate backend",
+                )?,
+                args.libretranslate_api_key.clone(),
+            )
+            .with_max_concurrent_requests(args.max_concurrent_requests)
+            .with_retry_policy(retry_policy),
+        ),
+        Backend::Openai => {
+            // The languages are built in, so listing them doesn't need a model
+            let model = if args.list_languages {
+                args.openai_model.clone().unwrap_or_default()
+            } else {
+                required(
+                    &args.openai_model,
+                    "--openai-model is required for the openai backend",
+                )?
+            };
+            let mut client = OpenAiClient::new(model, args.openai_api_key.clone())
+                .with_context_sentences(args.context_sentences)
+                .with_max_concurrent_requests(args.max_concurrent_requests)
+                .with_retry_policy(retry_policy);
+            if let Some(openai_url) = &args.openai_url {
+                client = client.with_endpoint(openai_url);
+            }
+            Box::new(client)
+        }
+        Backend::Pseudo => Box::new(PseudoTranslator::new()),
+    };
+    Ok(translator)
+}
+
+/// The text of the first cues, for detecting the language of the file.
+fn detection_sample(vtt: &Vtt) -> Vec<String> {
+    let mut sample = vec![];
+    let mut sample_chars = 0;
+    for block in vtt.blocks.iter() {
+        let text = cue_text::plain_text(&cue_text::tokenize(&block.text_lines.join(" ")));
+        let text = text.trim();
+        if text.is_empty() {
+            continue;
+        }
+        // Cut the last cue short rather than go over the limit
+        let text: String = text
+            .chars()
+            .take(DETECTION_SAMPLE_CHARS - sample_chars)
+            .collect();
+        sample_chars += text.chars().count();
+        sample.push(text);
+        if sample_chars >= DETECTION_SAMPLE_CHARS {
+            break;
+        }
+    }
+    sample
+}
+
+/// The estimated cost of sending this many characters to the translation API.
+fn cost(chars: usize, price_per_million_chars: f64) -> f64 {
+    chars as f64 * price_per_million_chars / 1_000_000.0
+}
+
+/// Report the characters which would be sent to the translation API to translate the sentences into each target language, leaving out those in the translation cache, and the estimated cost.
+fn print_estimate(
+    args: &Cli,
+    translator: &dyn Translator,
+    memory: Option<&TranslationMemory>,
+    sentences: &[String],
+    target_languages: &[Language],
+    detection_chars: usize,
+) -> Result<()> {
+    println!(
+        "Dry run: estimating the characters the {} translation API would bill for...",
+        translator.name()
+    );
+    let mut total_chars = detection_chars;
+    if detection_chars > 0 {
+        println!(
+            "Detecting the source language: {} characters, estimated cost {:.2}",
+            detection_chars,
+            cost(detection_chars, args.price_per_million_chars)
+        );
+        if memory.is_some() {
+            println!("The translation cache can't be checked without --source-language");
+        }
+    }
+    for target_language in target_languages {
+        let mut uncached = vec![];
+        for sentence in sentences {
+            let cached = match (memory, &args.source_language) {
+                (Some(memory), Some(source_language)) => memory
+                    .peek(
+                        &translator.cache_key(),
+                        &translator.options_key(),
+                        source_language,
+                        target_language,
+                        sentence,
+                    )?
+                    .is_some(),
+                _ => false,
+            };
+            if !cached {
+                uncached.push(sentence.clone());
+            }
+        }
+        let chars = translator.billed_chars(&uncached, target_language)?;
+        total_chars += chars;
+        println!(
+            "Translating into {}: {} characters, estimated cost {:.2}{}",
+            target_language,
+            chars,
+            cost(chars, args.price_per_million_chars),
+            if memory.is_some() {
+                format!(
+                    " ({} of {} sentences in the translation cache)",
+                    sentences.len() - uncached.len(),
+                    sentences.len()
+                )
+            } else {
+                String::new()
+            }
+        );
+    }
+    println!(
+        "Total: {} characters, estimated cost {:.2}",
+        total_chars,
+        cost(total_chars, args.price_per_million_chars)
+    );
+    if let Some(max_billed_chars) = args.max_billed_chars {
+        if total_chars > max_billed_chars {
+            println!(
+                "Warning: this exceeds the budget of {} characters, so translating would fail, without sending any request which would exceed it",
+                max_billed_chars
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Report the characters sent to the translation API, and the estimated cost.
+fn print_usage(usage: &Usage, price_per_million_chars: f64) {
+    let mut breakdown = vec![];
+    if usage.detection_chars > 0 {
+        breakdown.push(format!("{} to detect the language", usage.detection_chars));
+    }
+    for (target_language, chars) in usage.translation_chars.iter() {
+        breakdown.push(format!("{} to translate into {}", chars, target_language));
+    }
+    println!(
+        "Sent {} characters to the translation API{}, estimated cost {:.2}...",
+        usage.total(),
+        if breakdown.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", breakdown.join(", "))
+        },
+        cost(usage.total(), price_per_million_chars)
+    );
+}
+
+/// Import translations into the translation cache, prune it and export it, as requested.
+fn manage_translation_cache(memory: &TranslationMemory, args: &Cli) -> Result<()> {
+    if let Some(import_tmx) = &args.import_tmx {
+        let imported = memory.import_tmx(import_tmx)?;
+        println!("Imported {} translations from {:?}", imported, import_tmx);
+    }
+    if let Some(days) = args.prune_translation_cache {
+        let removed = memory.prune(Duration::from_secs(days * 24 * 60 * 60))?;
+        println!(
+            "Removed {} translations which haven't been used for {} days",
+            removed, days
+        );
+    }
+    if let Some(export_tmx) = &args.export_tmx {
+        let exported = memory.export_tmx(export_tmx)?;
+        println!("Exported {} translations to {:?}", exported, export_tmx);
+    }
+    Ok(())
+}
+
 pub async fn run(args: Cli) -> Result<()> {
-    // Parse the vtt file
-    println!("Parsing VTT file {:?}...", args.source_vtt_file);
-    let from_vtt = Vtt::parse(&args.source_vtt_file)?;
-    
-    // Scan the Vec of Blocks and convert to a Vec of whole sentences
-    let mut all_sentences = recontruct_sentences(&from_vtt);
-
-    // Translate the full sentences
-    let translation_client =
-        TranslationClient::new(args.azure_resource_key, args.azure_resource_region);
-    let from_sentences = all_sentences
+    let memory = match &args.translation_cache {
+        // A dry run only estimates, so mustn't change the cache
+        Some(translation_cache) if args.dry_run => Some(Arc::new(
+            TranslationMemory::open_read_only(translation_cache)?,
+        )),
+        Some(translation_cache) => Some(Arc::new(TranslationMemory::open(translation_cache)?)),
+        None => None,
+    };
+    if let Some(memory) = &memory {
+        if args.import_tmx.is_some()
+            || args.prune_translation_cache.is_some()
+            || args.export_tmx.is_some()
+        {
+            return manage_translation_cache(memory, &args);
+        }
+    }
+
+    let usage = Arc::new(Mutex::new(Usage::default()));
+    let mut metered_translator = MeteredTranslator::new(create_translator(&args)?, usage.clone());
+    if let Some(max_billed_chars) = args.max_billed_chars {
+        metered_translator = metered_translator.with_max_chars(max_billed_chars);
+    }
+    let mut translator: Box<dyn Translator> = Box::new(metered_translator);
+    if let Some(memory) = &memory {
+        translator = Box::new(CachingTranslator::new(translator, memory.clone()));
+    }
+
+    // Check the languages are supported before doing any work, except in a dry run which mustn't call the API
+    let languages = if args.dry_run {
+        BTreeMap::new()
+    } else {
+        translate::supported_languages(translator.as_ref()).await?
+    };
+    if args.list_languages {
+        list_languages(&languages);
+        return Ok(());
+    }
+    let supported = |language: &Language| {
+        if args.dry_run {
+            Ok(language.clone())
+        } else {
+            language.supported(&languages)
+        }
+    };
+    let mut target_languages = vec![];
+    for target_language in args.target_language.iter() {
+        let target_language = supported(target_language)?;
+        if !target_languages.contains(&target_language) {
+            target_languages.push(target_language);
+        }
+    }
+    if args.target_vtt_file.is_some() && target_languages.len() > 1 {
+        bail!("--target-vtt-file can't be used when translating to more than one language");
+    }
+    let source_language = args.source_language.as_ref().map(supported).transpose()?;
+    let source_vtt_file = args
+        .source_vtt_file
         .clone()
-        .into_iter()
-        .map(|(_cds, s)| s)
-        .collect::<Vec<String>>();
-    println!("Calling Azure translation API...");
-    let (source_language, direction, to_sentences) = translation_client
-        .translate(from_sentences, args.source_language, args.target_language)
+        .context("A source VTT file is required")?;
+
+    // Parse the vtt file
+    println!("Parsing VTT file {:?}...", source_vtt_file);
+    let from_vtt = Vtt::parse(&source_vtt_file)?;
+
+    // Scan the Vec of Blocks and convert to a Vec of whole sentences, using the punctuation rules of the source language
+    let detection_sample = detection_sample(&from_vtt);
+    let segmentation_language = match &source_language {
+        Some(source_language) => Some(source_language.clone()),
+        None if args.dry_run => None,
+        None => {
+            if detection_sample.is_empty() {
+                None
+            } else {
+                let detected_language = translator.detect_language(&detection_sample).await?;
+                println!("Detected source language as \"{}\"...", detected_language);
+                Some(detected_language)
+            }
+        }
+    };
+    let segmenter = SentenceSegmenter::new(
+        segmentation_language
+            .as_ref()
+            .map(|l| l.to_string())
+            .as_deref(),
+    );
+    let all_sentences = recontruct_sentences(&from_vtt, &segmenter);
+
+    // Mask the text which mustn't be translated
+    let protector = Protector::new(&args.protect, &args.protect_builtin)?;
+    let (from_sentences, protected): (Vec<String>, Vec<Vec<String>>) = all_sentences
+        .iter()
+        .map(|s| protector.mask(&s.text, translator.honours_notranslate()))
+        .unzip();
+
+    if args.dry_run {
+        let detection_chars = if source_language.is_none() {
+            billed_chars(&detection_sample)
+        } else {
+            0
+        };
+        return print_estimate(
+            &args,
+            translator.as_ref(),
+            memory.as_deref(),
+            &from_sentences,
+            &target_languages,
+            detection_chars,
+        );
+    }
+
+    // Translate the full sentences into all the target languages at once
+    println!("Calling {} translation API...", translator.name());
+    // Cached translations are keyed by the source language, so give the detected one rather than have the API detect it again
+    let translate_from = if memory.is_some() {
+        segmentation_language.as_ref()
+    } else {
+        source_language.as_ref()
+    };
+    let (source_language, all_translations) = translator
+        .translate(&from_sentences, translate_from, &target_languages)
         .await?;
     println!("Identified source language as \"{}\"...", source_language);
-    println!(
-        "Text direction for target language {} is {:?}...",
-        args.target_language, direction
-    );
-    all_sentences.iter_mut().enumerate().for_each(|(n, s)| {
-        s.1 = to_sentences[n].clone();
+    if let Some(memory) = &memory {
+        let (hits, misses) = memory.stats();
+        println!(
+            "Found {} of {} translations in the translation cache...",
+            hits,
+            hits + misses
+        );
+    }
+
+    let retime_options = args.retime.then(|| {
+        let defaults = RetimeOptions::default();
+        RetimeOptions {
+            max_line_chars: args.max_line_chars.unwrap_or(defaults.max_line_chars),
+            max_lines: args.max_lines.unwrap_or(defaults.max_lines),
+            max_chars_per_second: args
+                .max_chars_per_second
+                .unwrap_or(defaults.max_chars_per_second),
+            min_chars: args.min_cue_chars,
+            min_duration_ms: args.min_cue_duration_ms,
+            min_gap_ms: args.min_cue_gap_ms,
+        }
     });
+    let layout_limits = LayoutLimits {
+        max_line_chars: args.max_line_chars,
+        max_lines: args.max_lines,
+        max_chars_per_second: args.max_chars_per_second,
+    };
 
-    // Fill the translated sentences back into the vtt blocks
-    let mut to_vtt = from_vtt.clone();
-    update_vtt(&mut to_vtt, &all_sentences);
+    for (target_language, mut translations) in target_languages.iter().zip(all_translations) {
+        let direction = languages[&target_language.to_string()].direction;
+        println!(
+            "Text direction for target language {} is {:?}...",
+            target_language, direction
+        );
+        for (sentence_num, (t, p)) in translations.iter_mut().zip(protected.iter()).enumerate() {
+            if !p.is_empty() {
+                t.text = protect::restore(&t.text, p).with_context(|| {
+                    format!(
+                        "Failed to restore protected text in the {} translation of sentence {}",
+                        target_language,
+                        sentence_num + 1
+                    )
+                })?;
+                // The alignment is with the masked sentence, so doesn't match the original
+                t.alignment = None;
+            }
+        }
+        all_sentences
+            .iter()
+            .zip(translations.iter_mut())
+            .for_each(|(s, t)| {
+                let source_text = cue_text::plain_text(&cue_text::from_html(&s.text, &s.markup));
+                t.text =
+                    segment::mirror_terminator(&source_text, &t.text, &target_language.to_string());
+            });
 
-    // Write the translated vtt file
-    let target_vtt_file = match args.target_vtt_file {
-        Some(target_vtt_file) => target_vtt_file,
-        None => {
-            default_target_filename(&args.source_vtt_file, source_language, args.target_language)
+        // Fill the translated sentences back into the vtt blocks
+        let mut to_vtt = from_vtt.clone();
+        update_vtt(&mut to_vtt, &all_sentences, &translations);
+        to_vtt.set_language(&target_language.to_string());
+
+        // Adjust the cues to suit the length of the translated text
+        if let Some(retime_options) = &retime_options {
+            to_vtt = retime::retime(&to_vtt, retime_options);
+        }
+
+        // Re-wrap the translated text and report any cues which are too long to read
+        for (block_num, violation) in layout::apply(&mut to_vtt, &layout_limits) {
+            println!(
+                "Warning: {} cue at {}: {}",
+                target_language, to_vtt.blocks[block_num].start, violation
+            );
         }
-    };
-    println!("Writing translated VTT file to {:?}...", target_vtt_file);
-    to_vtt
-        .write(&target_vtt_file, crate::vtt::Direction::from(direction))
-        .with_context(|| format!("Failed to write to VTT file {:?}", target_vtt_file))?;
 
+        // Write the translated vtt file
+        let target_vtt_file = match &args.target_vtt_file {
+            Some(target_vtt_file) => target_vtt_file.clone(),
+            None => default_target_filename(&source_vtt_file, &source_language, target_language),
+        };
+        println!("Writing translated VTT file to {:?}...", target_vtt_file);
+        to_vtt
+            .write(&target_vtt_file, crate::vtt::Direction::from(direction))
+            .with_context(|| format!("Failed to write to VTT file {:?}", target_vtt_file))?;
+    }
+
+    print_usage(&usage.lock().unwrap(), args.price_per_million_chars);
     println!("Done");
     Ok(())
 }
@@ -242,42 +1152,250 @@ pub async fn run(args: Cli) -> Result<()> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_markup_survives_translation() {
+        let vtt = Vtt::parse_str(
+            "WEBVTT\n\n00:01.000 --> 00:02.000\n<v Fred>I <i>really\nmean</i> it. And\n\n00:02.000 --> 00:03.000\n<v Fred><c.loud>you</c>.\n",
+        )
+        .unwrap();
+        let sentences = recontruct_sentences(&vtt, &SentenceSegmenter::new(None));
+        assert_eq!(sentences.len(), 2);
+        assert_eq!(
+            sentences[0].text,
+            "I <span id=\"0\">really</span> <span id=\"1\">mean</span> it."
+        );
+        assert_eq!(sentences[1].chunk_descs.len(), 2);
+
+        // "Translate" by upper-casing the text outside of the markup
+        let mut translations = untranslated(&sentences);
+        translations[0].text =
+            "I <span id=\"0\">REALLY</span> <span id=\"1\">MEAN</span> IT.".to_string();
+        let mut to_vtt = vtt.clone();
+        update_vtt(&mut to_vtt, &sentences, &translations);
+        assert_eq!(
+            to_vtt.blocks[0].text_lines,
+            vec!["<v Fred>I <i>REALLY", "MEAN</i> IT. And</v>"]
+        );
+        assert_eq!(
+            to_vtt.blocks[1].text_lines,
+            vec!["<v Fred><c.loud>you</c>.</v>"]
+        );
+    }
+
+    #[test]
+    fn test_detection_sample_is_limited() {
+        let vtt = Vtt::parse_str(&format!(
+            "WEBVTT\n\n00:01.000 --> 00:02.000\n<v Fred>{0}\n\n00:02.000 --> 00:03.000\n{0}\n",
+            "word ".repeat(80)
+        ))
+        .unwrap();
+        let sample = detection_sample(&vtt);
+        assert_eq!(sample.len(), 2);
+        assert_eq!(billed_chars(&sample), DETECTION_SAMPLE_CHARS);
+    }
+
+    #[test]
+    fn test_sentences_split_at_speaker_change() {
+        let vtt = Vtt::parse_str(
+            "WEBVTT\n\n00:01.000 --> 00:02.000\n<v Fred>So I think\n\n00:02.000 --> 00:03.000\n<v Jim>Right</v> <v Fred>that we\n\n00:03.000 --> 00:04.000\n<v Fred>should.\n",
+        )
+        .unwrap();
+        let sentences = recontruct_sentences(&vtt, &SentenceSegmenter::new(None));
+        assert_eq!(
+            sentences
+                .iter()
+                .map(|s| s.text.as_str())
+                .collect::<Vec<_>>(),
+            vec!["So I think", "Right", "that we should."]
+        );
+
+        let mut to_vtt = vtt.clone();
+        update_vtt(&mut to_vtt, &sentences, &untranslated(&sentences));
+        assert_eq!(
+            to_vtt.blocks[1].text_lines,
+            vec!["<v Jim>Right</v> <v Fred>that we</v>"]
+        );
+        assert_eq!(to_vtt.blocks[2].text_lines, vec!["<v Fred>should.</v>"]);
+    }
+
+    fn untranslated(sentences: &[Sentence]) -> Vec<Translation> {
+        sentences
+            .iter()
+            .map(|s| Translation {
+                text: s.text.clone(),
+                alignment: None,
+            })
+            .collect()
+    }
+
+    fn translate_and_update(source: &str, translation: &str) -> Vec<Vec<String>> {
+        translate_and_update_aligned(source, translation, &[])
+    }
+
+    /// Translate the first sentence of `source`, aligning each of the given pairs of source and target words.
+    fn translate_and_update_aligned(
+        source: &str,
+        translation: &str,
+        aligned_words: &[(&str, &str)],
+    ) -> Vec<Vec<String>> {
+        let vtt = Vtt::parse_str(source).unwrap();
+        let sentences = recontruct_sentences(&vtt, &SentenceSegmenter::new(None));
+        let char_range = |text: &str, word: &str| {
+            let start = text[..text.find(word).unwrap()].chars().count();
+            start..start + word.chars().count()
+        };
+        let mut translations = untranslated(&sentences);
+        translations[0] = Translation {
+            text: translation.to_string(),
+            alignment: (!aligned_words.is_empty()).then(|| {
+                aligned_words
+                    .iter()
+                    .map(|(source_word, target_word)| translate::AlignmentSpan {
+                        source: char_range(&sentences[0].text, source_word),
+                        target: char_range(translation, target_word),
+                    })
+                    .collect()
+            }),
+        };
+        let mut to_vtt = vtt.clone();
+        update_vtt(&mut to_vtt, &sentences, &translations);
+        to_vtt.blocks.into_iter().map(|b| b.text_lines).collect()
+    }
+
+    #[test]
+    fn test_aligned_redistribution() {
+        let source = "WEBVTT\n\n00:01.000 --> 00:02.000\nHello my friend,\n\n00:02.000 --> 00:03.000\nhow are you today?\n";
+        let translation = "Bonjour à toi mon très cher ami, comment vas-tu aujourd'hui ?";
+
+        // Without alignment, the text is split in proportion to the source
+        assert_eq!(
+            translate_and_update(source, translation),
+            vec![
+                vec!["Bonjour à toi mon très cher"],
+                vec!["ami, comment vas-tu aujourd'hui ?"]
+            ]
+        );
+
+        // With alignment, each word lands in the cue containing the word it was translated from
+        assert_eq!(
+            translate_and_update_aligned(
+                source,
+                translation,
+                &[
+                    ("Hello", "Bonjour"),
+                    ("my", "mon"),
+                    ("friend", "ami"),
+                    ("how", "comment"),
+                    ("you", "vas-tu"),
+                    ("today", "aujourd'hui"),
+                ]
+            ),
+            vec![
+                vec!["Bonjour à toi mon très cher ami,"],
+                vec!["comment vas-tu aujourd'hui ?"]
+            ]
+        );
+
+        // Words which are reordered by the translation are kept in order
+        assert_eq!(
+            translate_and_update_aligned(
+                source,
+                "Aujourd'hui, comment vas-tu mon ami ?",
+                &[
+                    ("today", "Aujourd'hui"),
+                    ("how", "comment"),
+                    ("you", "vas-tu"),
+                    ("my", "mon"),
+                    ("friend", "ami"),
+                ]
+            ),
+            vec![vec![""], vec!["Aujourd'hui, comment vas-tu mon ami ?"]]
+        );
+    }
+
+    #[test]
+    fn test_monotonic_fit() {
+        assert_eq!(
+            monotonic_fit(&[Some(0), None, Some(2), Some(1), Some(2)], 3),
+            vec![0, 0, 1, 1, 2]
+        );
+        assert_eq!(
+            monotonic_fit(&[Some(1), Some(0), Some(0)], 2),
+            vec![0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_redistribution_uses_display_width() {
+        let source = "WEBVTT\n\n00:01.000 --> 00:02.000\nOne two three four\n\n00:02.000 --> 00:03.000\nfive six seven eight.\n";
+
+        // Farsi letters are two bytes each, but should be distributed the same as Latin letters
+        assert_eq!(
+            translate_and_update(
+                source,
+                "جلسه Teams امروز ساعت 10 با تیم پشتیبانی برگزار شد."
+            ),
+            vec![
+                vec!["جلسه Teams امروز ساعت"],
+                vec!["10 با تیم پشتیبانی برگزار شد."]
+            ]
+        );
+
+        // CJK characters are displayed at double width, so need half as many per cue
+        assert_eq!(
+            translate_and_update(
+                source,
+                "一二三四五六七八九十一二三四 五六七八九十一二三四五六七八。"
+            ),
+            vec![
+                vec!["一二三四五六七八九十一二三四"],
+                vec!["五六七八九十一二三四五六七八。"]
+            ]
+        );
+
+        // Emoji are a single grapheme cluster, despite being several code points
+        assert_eq!(
+            translate_and_update(source, "👨‍👩‍👧 👨‍👩‍👧 👨‍👩‍👧 👨‍👩‍👧 👨‍👩‍👧 👨‍👩‍👧 👨‍👩‍👧 👨‍👩‍👧 👍🏽 done."),
+            vec![vec!["👨‍👩‍👧 👨‍👩‍👧 👨‍👩‍👧 👨‍👩‍👧 👨‍👩‍👧"], vec!["👨‍👩‍👧 👨‍👩‍👧 👨‍👩‍👧 👍🏽 done."]]
+        );
+    }
+
+    fn lang(tag: &str) -> Language {
+        tag.parse().unwrap()
+    }
+
     #[test]
     fn test_default_target_file_stem() {
         assert_eq!(
-            default_target_filename(&Path::new("stem-en-GB.ext"), Language::EnGB, Language::Fa),
+            default_target_filename(Path::new("stem-en-GB.ext"), &lang("en-GB"), &lang("fa")),
             PathBuf::from("stem-fa.ext")
         );
         assert_eq!(
-            default_target_filename(&Path::new("stem-en-GB"), Language::EnGB, Language::Fa),
+            default_target_filename(Path::new("stem-en-GB"), &lang("en-GB"), &lang("fa")),
             PathBuf::from("stem-fa")
         );
         assert_eq!(
-            default_target_filename(&Path::new(".stem-en-GB"), Language::EnGB, Language::Fa),
+            default_target_filename(Path::new(".stem-en-GB"), &lang("en-GB"), &lang("fa")),
             PathBuf::from(".stem-fa")
         );
         assert_eq!(
-            default_target_filename(&Path::new(".stem-en-GB.ext"), Language::EnGB, Language::Fa),
+            default_target_filename(Path::new(".stem-en-GB.ext"), &lang("en-GB"), &lang("fa")),
             PathBuf::from(".stem-fa.ext")
         );
         assert_eq!(
-            default_target_filename(&Path::new("stem"), Language::EnGB, Language::Fa),
+            default_target_filename(Path::new("stem"), &lang("en-GB"), &lang("fa")),
             PathBuf::from("stem-fa")
         );
         assert_eq!(
             default_target_filename(
-                &Path::new("stem-more-stem-en-GB"),
-                Language::EnGB,
-                Language::Fa
+                Path::new("stem-more-stem-en-GB"),
+                &lang("en-GB"),
+                &lang("fa")
             ),
             PathBuf::from("stem-more-stem-fa")
         );
         assert_eq!(
-            default_target_filename(
-                &Path::new("stem-more-stem.ext"),
-                Language::EnGB,
-                Language::Fa
-            ),
+            default_target_filename(Path::new("stem-more-stem.ext"), &lang("en-GB"), &lang("fa")),
             PathBuf::from("stem-more-stem-fa.ext")
         );
     }
@@ -285,19 +1403,19 @@ mod tests {
     #[test]
     fn test_default_target_file_language() {
         assert_eq!(
-            default_target_filename(&Path::new("stem-en-gb.ext"), Language::EnGB, Language::Fa),
+            default_target_filename(Path::new("stem-en-gb.ext"), &lang("en-GB"), &lang("fa")),
             PathBuf::from("stem-fa.ext")
         );
         assert_eq!(
-            default_target_filename(&Path::new("stem-en-GB.ext"), Language::En, Language::Fa),
+            default_target_filename(Path::new("stem-en-GB.ext"), &lang("en"), &lang("fa")),
             PathBuf::from("stem-fa.ext")
         );
         assert_eq!(
-            default_target_filename(&Path::new("stem-en-us.ext"), Language::En, Language::Fa),
+            default_target_filename(Path::new("stem-en-us.ext"), &lang("en"), &lang("fa")),
             PathBuf::from("stem-fa.ext")
         );
         assert_eq!(
-            default_target_filename(&Path::new("stem-en.ext"), Language::En, Language::Fa),
+            default_target_filename(Path::new("stem-en.ext"), &lang("en"), &lang("fa")),
             PathBuf::from("stem-fa.ext")
         );
     }
@@ -305,29 +1423,29 @@ mod tests {
     fn test_default_target_directory() {
         assert_eq!(
             default_target_filename(
-                &Path::new("/directory/stem.ext"),
-                Language::EnGB,
-                Language::Fa
+                Path::new("/directory/stem.ext"),
+                &lang("en-GB"),
+                &lang("fa")
             ),
             PathBuf::from("/directory/stem-fa.ext")
         );
         assert_eq!(
-            default_target_filename(&Path::new("./stem.ext"), Language::EnGB, Language::Fa),
+            default_target_filename(Path::new("./stem.ext"), &lang("en-GB"), &lang("fa")),
             PathBuf::from("./stem-fa.ext")
         );
         assert_eq!(
             default_target_filename(
-                &Path::new("./directory/stem.ext"),
-                Language::EnGB,
-                Language::Fa
+                Path::new("./directory/stem.ext"),
+                &lang("en-GB"),
+                &lang("fa")
             ),
             PathBuf::from("./directory/stem-fa.ext")
         );
         assert_eq!(
             default_target_filename(
-                &Path::new("../directory/stem.ext"),
-                Language::EnGB,
-                Language::Fa
+                Path::new("../directory/stem.ext"),
+                &lang("en-GB"),
+                &lang("fa")
             ),
             PathBuf::from("../directory/stem-fa.ext")
         );
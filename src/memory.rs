@@ -0,0 +1,641 @@
+use crate::cue_text;
+use crate::translate::{AlignmentSpan, Language, Translation};
+use anyhow::{Context, Result};
+use quick_xml::escape::{escape, partial_escape};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use regex::Regex;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+/// TMX properties recording which translation API, with which options, made each translation.
+static BACKEND_PROP: &str = "x-vtt-translate-backend";
+static OPTIONS_PROP: &str = "x-vtt-translate-options";
+
+/// Tags in TMX segments which hold native markup, i.e. the HTML tags in sentences.
+static TMX_CODE_TAGS: [&[u8]; 4] = [b"ph", b"bpt", b"ept", b"it"];
+
+/// A sentence and its translation, as kept in the translation memory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// The translation API (its `cache_key`) which made the translation, or empty if it was imported from another tool.
+    pub backend: String,
+    /// The options the translation API was used with (its `options_key`).
+    pub options: String,
+    pub source_language: String,
+    pub target_language: String,
+    /// The source sentence, as HTML.
+    pub source: String,
+    /// The translation, as HTML.
+    pub target: String,
+}
+
+/// A translation memory of sentences which have been translated before, in an SQLite database, so that they don't need to be paid for again.
+///
+/// Translations are keyed by the translation API and the options it was used with, the source and target languages, and the source sentence with its whitespace normalised. Translations imported from other tools, without a translation API, are used with any.
+pub struct TranslationMemory {
+    connection: Mutex<Connection>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+/// The sentence with each run of whitespace replaced by a single space, and none at the ends.
+fn normalise_source(source: &str) -> String {
+    source.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Find a translation of the sentence by the translation API with these options, or imported from another tool, with its row id.
+fn find(
+    connection: &Connection,
+    backend: &str,
+    options: &str,
+    from: &Language,
+    to: &Language,
+    source: &str,
+) -> Result<Option<(i64, Translation)>> {
+    let found = connection
+        .query_row(
+            "SELECT rowid, target, alignment FROM translations
+            WHERE ((backend = ?1 AND options = ?2) OR backend = '')
+                AND source_language = ?3 AND target_language = ?4 AND source = ?5
+            ORDER BY backend = '' LIMIT 1",
+            params![
+                backend,
+                options,
+                from.to_string(),
+                to.to_string(),
+                normalise_source(source)
+            ],
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                ))
+            },
+        )
+        .optional()
+        .context("Failed to look up translation in translation cache")?;
+    let Some((rowid, text, alignment)) = found else {
+        return Ok(None);
+    };
+    let alignment = alignment
+        .and_then(|a| serde_json::from_str::<Vec<[usize; 4]>>(&a).ok())
+        .map(|spans| {
+            spans
+                .into_iter()
+                .map(|[s0, s1, t0, t1]| AlignmentSpan {
+                    source: s0..s1,
+                    target: t0..t1,
+                })
+                .collect()
+        });
+    Ok(Some((rowid, Translation { text, alignment })))
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+impl TranslationMemory {
+    /// Open the translation memory in this SQLite database, creating it if need be.
+    pub fn open(path: &Path) -> Result<TranslationMemory> {
+        let connection = Connection::open(path)
+            .with_context(|| format!("Failed to open translation cache {:?}", path))?;
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS translations (
+                    backend TEXT NOT NULL,
+                    options TEXT NOT NULL,
+                    source_language TEXT NOT NULL COLLATE NOCASE,
+                    target_language TEXT NOT NULL COLLATE NOCASE,
+                    source TEXT NOT NULL,
+                    target TEXT NOT NULL,
+                    alignment TEXT,
+                    last_used INTEGER NOT NULL,
+                    PRIMARY KEY (backend, options, source_language, target_language, source)
+                );",
+            )
+            .with_context(|| format!("Failed to create translation cache {:?}", path))?;
+        Ok(TranslationMemory {
+            connection: Mutex::new(connection),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        })
+    }
+
+    /// Open the translation memory in this SQLite database without changing it, e.g. for a dry run. A database which doesn't exist is treated as empty, and isn't created.
+    pub fn open_read_only(path: &Path) -> Result<TranslationMemory> {
+        if !path.exists() {
+            return TranslationMemory::open(Path::new(":memory:"));
+        }
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .with_context(|| format!("Failed to open translation cache {:?}", path))?;
+        Ok(TranslationMemory {
+            connection: Mutex::new(connection),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        })
+    }
+
+    /// Look up a translation of the sentence by the translation API with these options, or imported from another tool.
+    pub fn lookup(
+        &self,
+        backend: &str,
+        options: &str,
+        from: &Language,
+        to: &Language,
+        source: &str,
+    ) -> Result<Option<Translation>> {
+        let connection = self.connection.lock().unwrap();
+        let Some((rowid, translation)) = find(&connection, backend, options, from, to, source)?
+        else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return Ok(None);
+        };
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        connection.execute(
+            "UPDATE translations SET last_used = ?1 WHERE rowid = ?2",
+            params![now(), rowid],
+        )?;
+        Ok(Some(translation))
+    }
+
+    /// Look up a translation as `lookup` does, but without recording that it was used, or counting it in the stats.
+    pub fn peek(
+        &self,
+        backend: &str,
+        options: &str,
+        from: &Language,
+        to: &Language,
+        source: &str,
+    ) -> Result<Option<Translation>> {
+        let connection = self.connection.lock().unwrap();
+        Ok(find(&connection, backend, options, from, to, source)?.map(|(_, t)| t))
+    }
+
+    /// Add translations of sentences by the translation API with these options, replacing any it made before.
+    pub fn insert<'a>(
+        &self,
+        backend: &str,
+        options: &str,
+        from: &Language,
+        to: &Language,
+        translations: impl IntoIterator<Item = (&'a String, &'a Translation)>,
+    ) -> Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        for (source, translation) in translations {
+            let alignment = translation.alignment.as_ref().map(|spans| {
+                let spans: Vec<[usize; 4]> = spans
+                    .iter()
+                    .map(|s| [s.source.start, s.source.end, s.target.start, s.target.end])
+                    .collect();
+                serde_json::to_string(&spans).unwrap()
+            });
+            transaction.execute(
+                "INSERT OR REPLACE INTO translations VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    backend,
+                    options,
+                    from.to_string(),
+                    to.to_string(),
+                    normalise_source(source),
+                    translation.text,
+                    alignment,
+                    now()
+                ],
+            )?;
+        }
+        transaction
+            .commit()
+            .context("Failed to add translations to translation cache")
+    }
+
+    /// The number of sentences found in the translation memory, and not found, so far.
+    pub fn stats(&self) -> (usize, usize) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Remove translations which haven't been used for this long, returning how many were removed.
+    pub fn prune(&self, unused_for: Duration) -> Result<usize> {
+        let connection = self.connection.lock().unwrap();
+        let removed = connection
+            .execute(
+                "DELETE FROM translations WHERE last_used < ?1",
+                params![
+                    now().saturating_sub(i64::try_from(unused_for.as_secs()).unwrap_or(i64::MAX))
+                ],
+            )
+            .context("Failed to prune translation cache")?;
+        connection.execute_batch("VACUUM")?;
+        Ok(removed)
+    }
+
+    /// All the translations, ordered by translation API, languages and source sentence.
+    pub fn entries(&self) -> Result<Vec<Entry>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT backend, options, source_language, target_language, source, target
+            FROM translations ORDER BY backend, options, source_language, target_language, source",
+        )?;
+        let entries = statement
+            .query_map([], |row| {
+                Ok(Entry {
+                    backend: row.get(0)?,
+                    options: row.get(1)?,
+                    source_language: row.get(2)?,
+                    target_language: row.get(3)?,
+                    source: row.get(4)?,
+                    target: row.get(5)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(entries)
+    }
+
+    /// Add translations from another translation memory, e.g. one imported from TMX, replacing any with the same key.
+    pub fn import(&self, entries: &[Entry]) -> Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        for entry in entries {
+            transaction.execute(
+                "INSERT OR REPLACE INTO translations VALUES (?1, ?2, ?3, ?4, ?5, ?6, NULL, ?7)",
+                params![
+                    entry.backend,
+                    entry.options,
+                    entry.source_language,
+                    entry.target_language,
+                    normalise_source(&entry.source),
+                    entry.target,
+                    now()
+                ],
+            )?;
+        }
+        transaction
+            .commit()
+            .context("Failed to import translations into translation cache")
+    }
+
+    /// Export the translations to a TMX file, returning how many there were.
+    pub fn export_tmx(&self, path: &Path) -> Result<usize> {
+        let entries = self.entries()?;
+        std::fs::write(path, to_tmx(&entries))
+            .with_context(|| format!("Failed to write TMX file {:?}", path))?;
+        Ok(entries.len())
+    }
+
+    /// Import the translations in a TMX file, returning how many there were.
+    pub fn import_tmx(&self, path: &Path) -> Result<usize> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read TMX file {:?}", path))?;
+        let entries =
+            from_tmx(&contents).with_context(|| format!("Failed to parse TMX file {:?}", path))?;
+        self.import(&entries)?;
+        Ok(entries.len())
+    }
+}
+
+/// Convert a sentence from HTML to the content of a TMX segment, with its tags as placeholders.
+fn tmx_segment(html: &str) -> String {
+    static TAG_RE: OnceLock<Regex> = OnceLock::new();
+    let tag_re = TAG_RE.get_or_init(|| Regex::new(r"<[^>]*>").unwrap());
+    let mut segment = String::new();
+    let mut text_start = 0;
+    for tag in tag_re.find_iter(html) {
+        segment.push_str(&partial_escape(&cue_text::unescape_html(
+            &html[text_start..tag.start()],
+        )));
+        segment.push_str(&format!("<ph>{}</ph>", partial_escape(tag.as_str())));
+        text_start = tag.end();
+    }
+    segment.push_str(&partial_escape(&cue_text::unescape_html(
+        &html[text_start..],
+    )));
+    segment
+}
+
+/// Write translations as a TMX 1.4 document, with a translation unit for each.
+pub fn to_tmx(entries: &[Entry]) -> String {
+    let mut tmx =
+        String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<tmx version=\"1.4\">\n");
+    tmx.push_str(&format!(
+        "  <header creationtool=\"vtt-translate\" creationtoolversion=\"{}\" segtype=\"sentence\" o-tmf=\"vtt-translate\" adminlang=\"en\" srclang=\"*all*\" datatype=\"html\"/>\n  <body>\n",
+        env!("CARGO_PKG_VERSION")
+    ));
+    for entry in entries {
+        tmx.push_str(&format!(
+            "    <tu srclang=\"{}\">\n",
+            escape(&entry.source_language)
+        ));
+        if !entry.backend.is_empty() {
+            tmx.push_str(&format!(
+                "      <prop type=\"{}\">{}</prop>\n",
+                BACKEND_PROP,
+                partial_escape(&entry.backend)
+            ));
+            tmx.push_str(&format!(
+                "      <prop type=\"{}\">{}</prop>\n",
+                OPTIONS_PROP,
+                partial_escape(&entry.options)
+            ));
+        }
+        for (language, html) in [
+            (&entry.source_language, &entry.source),
+            (&entry.target_language, &entry.target),
+        ] {
+            tmx.push_str(&format!(
+                "      <tuv xml:lang=\"{}\"><seg>{}</seg></tuv>\n",
+                escape(language),
+                tmx_segment(html)
+            ));
+        }
+        tmx.push_str("    </tu>\n");
+    }
+    tmx.push_str("  </body>\n</tmx>\n");
+    tmx
+}
+
+fn attribute(element: &BytesStart, name: &str) -> Result<Option<String>> {
+    Ok(match element.try_get_attribute(name)? {
+        Some(attribute) => Some(attribute.unescape_value()?.into_owned()),
+        None => None,
+    })
+}
+
+/// Read the translations in a TMX document, taking each variant of a translation unit other than the source as a translation of it.
+///
+/// Translation units without a source language (in the unit or the header) take their first variant as the source. Units written by other tools don't say which translation API made them, so are used with any.
+pub fn from_tmx(tmx: &str) -> Result<Vec<Entry>> {
+    let mut reader = Reader::from_str(tmx);
+    let mut entries = vec![];
+    let mut header_source_language = None;
+    let mut unit_source_language = None;
+    let mut props: Vec<(String, String)> = vec![];
+    let mut variants: Vec<(String, String)> = vec![];
+    let mut prop_type = None;
+    let mut variant_language = None;
+    let mut text = String::new();
+    let mut segment = None;
+    let mut in_code = false;
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) | Event::Empty(element)
+                if element.name().as_ref() == b"header" =>
+            {
+                header_source_language = attribute(&element, "srclang")?;
+            }
+            Event::Start(element) => match element.name().as_ref() {
+                b"tu" => {
+                    unit_source_language = attribute(&element, "srclang")?;
+                    props.clear();
+                    variants.clear();
+                }
+                b"prop" => {
+                    prop_type = attribute(&element, "type")?;
+                    text.clear();
+                }
+                b"tuv" => {
+                    // TMX 1.1 used `lang` rather than `xml:lang`
+                    variant_language = match attribute(&element, "xml:lang")? {
+                        Some(language) => Some(language),
+                        None => attribute(&element, "lang")?,
+                    };
+                }
+                b"seg" => segment = Some(String::new()),
+                name if TMX_CODE_TAGS.contains(&name) => in_code = true,
+                _ => {}
+            },
+            Event::Text(content) => {
+                let content = content.unescape()?;
+                match &mut segment {
+                    // Placeholders hold the sentence's tags, and the text needs escaping as HTML
+                    Some(segment) if in_code => segment.push_str(&content),
+                    Some(segment) => segment.push_str(&cue_text::escape_html(&content)),
+                    None => text.push_str(&content),
+                }
+            }
+            Event::End(element) => match element.name().as_ref() {
+                b"prop" => {
+                    if let Some(prop_type) = prop_type.take() {
+                        props.push((prop_type, std::mem::take(&mut text)));
+                    }
+                }
+                b"seg" => {
+                    if let (Some(language), Some(segment)) =
+                        (variant_language.take(), segment.take())
+                    {
+                        variants.push((language, segment));
+                    }
+                }
+                b"tu" => {
+                    let prop = |name: &str| {
+                        props
+                            .iter()
+                            .find(|(prop_type, _)| prop_type == name)
+                            .map(|(_, value)| value.clone())
+                            .unwrap_or_default()
+                    };
+                    let source_language = unit_source_language
+                        .take()
+                        .or_else(|| header_source_language.clone())
+                        .filter(|l| l != "*all*")
+                        .or_else(|| variants.first().map(|(l, _)| l.clone()));
+                    let Some(source_language) = source_language else {
+                        continue;
+                    };
+                    let Some((_, source)) = variants
+                        .iter()
+                        .find(|(l, _)| l.eq_ignore_ascii_case(&source_language))
+                    else {
+                        continue;
+                    };
+                    for (target_language, target) in variants.iter() {
+                        if target_language.eq_ignore_ascii_case(&source_language) {
+                            continue;
+                        }
+                        entries.push(Entry {
+                            backend: prop(BACKEND_PROP),
+                            options: prop(OPTIONS_PROP),
+                            source_language: source_language.clone(),
+                            target_language: target_language.clone(),
+                            source: source.clone(),
+                            target: target.clone(),
+                        });
+                    }
+                }
+                name if TMX_CODE_TAGS.contains(&name) => in_code = false,
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn language(tag: &str) -> Language {
+        tag.parse().unwrap()
+    }
+
+    fn translation(text: &str) -> Translation {
+        Translation {
+            text: text.to_string(),
+            alignment: None,
+        }
+    }
+
+    #[test]
+    fn test_lookup_and_prune() {
+        let memory = TranslationMemory::open(Path::new(":memory:")).unwrap();
+        let (en, fr) = (language("en"), language("fr"));
+        let source = "Hello <span id=\"0\">world</span>.".to_string();
+        let aligned = Translation {
+            text: "Bonjour <span id=\"0\">le monde</span>.".to_string(),
+            alignment: Some(vec![AlignmentSpan {
+                source: 0..4,
+                target: 0..6,
+            }]),
+        };
+        memory
+            .insert("Azure", "", &en, &fr, [(&source, &aligned)])
+            .unwrap();
+
+        // Whitespace and the case of language tags don't matter, but the translation API and its options do
+        let found = memory
+            .lookup(
+                "Azure",
+                "",
+                &en,
+                &language("FR"),
+                " Hello  <span id=\"0\">world</span>.\n",
+            )
+            .unwrap();
+        assert_eq!(found, Some(aligned));
+        assert_eq!(memory.lookup("DeepL", "", &en, &fr, &source).unwrap(), None);
+        assert_eq!(
+            memory
+                .lookup("Azure", "{\"category\":\"x\"}", &en, &fr, &source)
+                .unwrap(),
+            None
+        );
+        assert_eq!(memory.stats(), (1, 2));
+
+        // Imported translations are used with any translation API, unless it has its own
+        memory
+            .import(&[Entry {
+                backend: String::new(),
+                options: String::new(),
+                source_language: "en".to_string(),
+                target_language: "fr".to_string(),
+                source: source.clone(),
+                target: "Salut <span id=\"0\">le monde</span>.".to_string(),
+            }])
+            .unwrap();
+        assert_eq!(
+            memory.lookup("DeepL", "", &en, &fr, &source).unwrap(),
+            Some(translation("Salut <span id=\"0\">le monde</span>."))
+        );
+        assert_eq!(
+            memory
+                .lookup("Azure", "", &en, &fr, &source)
+                .unwrap()
+                .unwrap()
+                .text,
+            "Bonjour <span id=\"0\">le monde</span>."
+        );
+
+        assert_eq!(memory.prune(Duration::from_secs(3600)).unwrap(), 0);
+        assert_eq!(memory.prune(Duration::ZERO).unwrap(), 0);
+        assert_eq!(memory.entries().unwrap().len(), 2);
+
+        // Only translations which haven't been used recently are pruned
+        memory
+            .connection
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE translations SET last_used = ?1 WHERE backend = ''",
+                params![now() - 7200],
+            )
+            .unwrap();
+        assert_eq!(memory.prune(Duration::from_secs(3600)).unwrap(), 1);
+        let entries = memory.entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].backend, "Azure");
+        assert_eq!(memory.prune(Duration::from_secs(u64::MAX)).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_read_only() {
+        let path = std::env::temp_dir().join(format!("vtt-translate-{}.sqlite", Uuid::new_v4()));
+        let (en, fr) = (language("en"), language("fr"));
+        let source = "Hello.".to_string();
+
+        // A missing database isn't created
+        let memory = TranslationMemory::open_read_only(&path).unwrap();
+        assert_eq!(memory.peek("Azure", "", &en, &fr, &source).unwrap(), None);
+        assert!(!path.exists());
+
+        TranslationMemory::open(&path)
+            .unwrap()
+            .insert("Azure", "", &en, &fr, [(&source, &translation("Bonjour."))])
+            .unwrap();
+        let memory = TranslationMemory::open_read_only(&path).unwrap();
+        assert_eq!(
+            memory.peek("Azure", "", &en, &fr, &source).unwrap(),
+            Some(translation("Bonjour."))
+        );
+        assert_eq!(memory.stats(), (0, 0));
+        assert!(memory
+            .insert("Azure", "", &en, &fr, [(&source, &translation("Salut."))])
+            .is_err());
+        drop(memory);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tmx_round_trip() {
+        let entries = vec![Entry {
+            backend: "Azure".to_string(),
+            options: "{\"category\":\"x\"}".to_string(),
+            source_language: "en".to_string(),
+            target_language: "fr".to_string(),
+            source: "<span id=\"0\">R&amp;D</span> is &lt;here&gt;".to_string(),
+            target: "<span id=\"0\">R&amp;D</span> est &lt;ici&gt;".to_string(),
+        }];
+        let tmx = to_tmx(&entries);
+        assert!(tmx.contains(
+            "<tuv xml:lang=\"en\"><seg><ph>&lt;span id=\"0\"&gt;</ph>R&amp;D<ph>&lt;/span&gt;</ph> is &lt;here&gt;</seg></tuv>"
+        ));
+        assert_eq!(from_tmx(&tmx).unwrap(), entries);
+
+        // TMX from another tool, with several target languages and inline markup
+        let tmx = r#"<?xml version="1.0"?>
+<tmx version="1.4"><header srclang="en-US" segtype="sentence"/><body>
+<tu><tuv xml:lang="en-US"><seg>Click <bpt i="1">&lt;b&gt;</bpt>Save<ept i="1">&lt;/b&gt;</ept> &amp; exit</seg></tuv>
+<tuv xml:lang="de-DE"><seg>Klicken Sie auf Speichern</seg></tuv>
+<tuv xml:lang="fr-FR"><seg>Cliquez sur <hi>Enregistrer</hi></seg></tuv></tu>
+</body></tmx>"#;
+        let entries = from_tmx(tmx).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].backend, "");
+        assert_eq!(entries[0].source_language, "en-US");
+        assert_eq!(entries[0].source, "Click <b>Save</b> &amp; exit");
+        assert_eq!(entries[1].target_language, "fr-FR");
+        assert_eq!(entries[1].target, "Cliquez sur Enregistrer");
+    }
+}
@@ -0,0 +1,239 @@
+use crate::cue_text::{escape_html, unescape_html};
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use std::sync::OnceLock;
+
+/// Built-in detectors of text which must not be translated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProtectBuiltin {
+    /// Web addresses, e.g. `https://example.com/a?b=c` or `www.example.com`
+    Url,
+    /// Email addresses, e.g. `dev@example.com`
+    Email,
+    /// Code identifiers, e.g. `snake_case`, `camelCase`, `foo::bar`, `foo()` or anything in backticks
+    Code,
+    /// Version numbers with a `v` prefix or at least three parts, e.g. `v1.2`, `2.0.1` or `3.0.0-beta.1`, but not decimals such as `1.5`
+    Version,
+}
+
+impl ProtectBuiltin {
+    fn pattern(self) -> &'static str {
+        match self {
+            ProtectBuiltin::Url => r#"\b(?:https?://|www\.)[^\s<>"]*[^\s<>".,;:!?)\]]"#,
+            ProtectBuiltin::Email => r"\b[\w.+-]+@[\w-]+(?:\.[\w-]+)+\b",
+            ProtectBuiltin::Code => {
+                r"`[^`]+`|\b\w+(?:::\w+)+\b|\b[A-Za-z]\w*\(\)|\b[A-Za-z][A-Za-z0-9]*(?:_[A-Za-z0-9]+)+\b|\b[a-z]+[0-9]*(?:[A-Z][a-z0-9]*)+\b"
+            }
+            ProtectBuiltin::Version => {
+                r"\b(?:v[0-9]+(?:\.[0-9]+)+|[0-9]+(?:\.[0-9]+){2,})(?:-[0-9A-Za-z.]*[0-9A-Za-z])?\b"
+            }
+        }
+    }
+}
+
+/// Finds text in sentences which must not be translated, such as names, code and URLs, and masks it from the translation API.
+///
+/// Translation APIs which honour `<span class="notranslate">` see the protected text wrapped in one, and others see an empty `<span>` placeholder in its place. Either way, the tag has an id of the form `dntN` and is replaced by the original text after translation.
+pub struct Protector {
+    protected_re: Option<Regex>,
+}
+
+impl Protector {
+    /// Protect text matching any of the regular expressions, or the built-in detectors.
+    pub fn new(patterns: &[String], builtins: &[ProtectBuiltin]) -> Result<Protector> {
+        let mut alternatives = vec![];
+        for pattern in patterns {
+            Regex::new(pattern)
+                .with_context(|| format!("Invalid regular expression {:?}", pattern))?;
+            alternatives.push(format!("(?:{})", pattern));
+        }
+        alternatives.extend(builtins.iter().map(|b| format!("(?:{})", b.pattern())));
+        let protected_re = if alternatives.is_empty() {
+            None
+        } else {
+            Some(Regex::new(&alternatives.join("|"))?)
+        };
+        Ok(Protector { protected_re })
+    }
+
+    /// Mask the protected text in the text (but not the tags) of an HTML sentence, returning the masked sentence and the protected text (as HTML), indexed by the number in each tag's id.
+    ///
+    /// The patterns are matched against the text with its character references decoded, e.g. `AT&T` rather than `AT&amp;T`.
+    pub fn mask(&self, html: &str, notranslate: bool) -> (String, Vec<String>) {
+        let Some(protected_re) = &self.protected_re else {
+            return (html.to_string(), vec![]);
+        };
+        static TAG_RE: OnceLock<Regex> = OnceLock::new();
+        let tag_re = TAG_RE.get_or_init(|| Regex::new(r"<[^>]*>").unwrap());
+
+        let mut protected = vec![];
+        let mut mask_text = |html_text: &str| -> String {
+            let text = unescape_html(html_text);
+            let mut masked = String::new();
+            let mut last_end = 0;
+            for m in protected_re.find_iter(&text) {
+                masked.push_str(&escape_html(&text[last_end..m.start()]));
+                let id = protected.len();
+                let protected_html = escape_html(m.as_str());
+                if notranslate {
+                    masked.push_str(&format!(
+                        "<span class=\"notranslate\" id=\"dnt{}\">{}</span>",
+                        id, protected_html
+                    ));
+                } else {
+                    masked.push_str(&format!("<span id=\"dnt{}\"></span>", id));
+                }
+                protected.push(protected_html);
+                last_end = m.end();
+            }
+            masked.push_str(&escape_html(&text[last_end..]));
+            masked
+        };
+        let mut masked = String::new();
+        let mut text_start = 0;
+        for tag in tag_re.find_iter(html) {
+            masked.push_str(&mask_text(&html[text_start..tag.start()]));
+            masked.push_str(tag.as_str());
+            text_start = tag.end();
+        }
+        masked.push_str(&mask_text(&html[text_start..]));
+        (masked, protected)
+    }
+}
+
+/// Replace the tags in a translation of a masked sentence with the protected text, which must all still be there.
+pub fn restore(translation: &str, protected: &[String]) -> Result<String> {
+    static PROTECTED_TAG_RE: OnceLock<Regex> = OnceLock::new();
+    let protected_tag_re = PROTECTED_TAG_RE.get_or_init(|| {
+        Regex::new(r#"(?s)<span\b[^>]*\bid\s*=\s*["']?dnt([0-9]+)["']?[^>]*>.*?</span>"#).unwrap()
+    });
+    let mut restored = vec![false; protected.len()];
+    let mut unknown = None;
+    let translation = protected_tag_re.replace_all(translation, |captures: &regex::Captures| {
+        match captures[1]
+            .parse::<usize>()
+            .ok()
+            .filter(|&id| id < protected.len())
+        {
+            Some(id) => {
+                restored[id] = true;
+                protected[id].clone()
+            }
+            None => {
+                unknown.get_or_insert(captures[0].to_string());
+                String::new()
+            }
+        }
+    });
+    if let Some(unknown) = unknown {
+        bail!("Translation contains unknown protected text {:?}", unknown);
+    }
+    if let Some(missing) = restored.iter().position(|r| !r) {
+        bail!(
+            "Translation API dropped protected text {:?} from the translation {:?}",
+            protected[missing],
+            translation
+        );
+    }
+    Ok(translation.into_owned())
+}
+
+/// Whether a translation of a masked sentence still has all its protected text, so that `restore` will succeed.
+pub fn keeps_protected_text(masked: &str, translation: &str) -> bool {
+    static PROTECTED_ID_RE: OnceLock<Regex> = OnceLock::new();
+    let protected_id_re =
+        PROTECTED_ID_RE.get_or_init(|| Regex::new(r#"<span\b[^>]*\bid="dnt[0-9]+""#).unwrap());
+    let count = protected_id_re.find_iter(masked).count();
+    restore(translation, &vec![String::new(); count]).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mask_and_restore() {
+        let protector = Protector::new(
+            &["Jürgen".to_string()],
+            &[
+                ProtectBuiltin::Url,
+                ProtectBuiltin::Email,
+                ProtectBuiltin::Code,
+                ProtectBuiltin::Version,
+            ],
+        )
+        .unwrap();
+        let html = "Jürgen: see <span id=\"0\">https://example.com/a?b=c&amp;d</span>, \
+                    mail dev@example.com, call parse_vtt() in v1.2.3.";
+        let (masked, protected) = protector.mask(html, false);
+        assert_eq!(
+            masked,
+            "<span id=\"dnt0\"></span>: see <span id=\"0\"><span id=\"dnt1\"></span></span>, \
+             mail <span id=\"dnt2\"></span>, call <span id=\"dnt3\"></span> in <span id=\"dnt4\"></span>."
+        );
+        assert_eq!(
+            protected,
+            vec![
+                "Jürgen",
+                "https://example.com/a?b=c&amp;d",
+                "dev@example.com",
+                "parse_vtt()",
+                "v1.2.3"
+            ]
+        );
+
+        // The translation API may move the protected text around
+        let translation = "<span id=\"dnt0\"></span>: voir <span id=\"0\"><span id=\"dnt1\"></span></span>, \
+                           <span id=\"dnt4\"></span> <span id=\"dnt3\"></span> <span id=\"dnt2\"></span>.";
+        assert_eq!(
+            restore(translation, &protected).unwrap(),
+            "Jürgen: voir <span id=\"0\">https://example.com/a?b=c&amp;d</span>, \
+             v1.2.3 parse_vtt() dev@example.com."
+        );
+        assert!(keeps_protected_text(&masked, translation));
+        assert!(!keeps_protected_text(
+            &masked,
+            "<span id=\"dnt0\"></span> <span id=\"dnt1\"></span>"
+        ));
+        assert!(restore("<span id=\"dnt0\"></span>", &protected)
+            .unwrap_err()
+            .to_string()
+            .contains("dropped protected text \"https://example.com/a?b=c&amp;d\""));
+
+        let (masked, protected) = protector.mask("Ask Jürgen", true);
+        assert_eq!(
+            masked,
+            "Ask <span class=\"notranslate\" id=\"dnt0\">Jürgen</span>"
+        );
+        assert_eq!(
+            restore(
+                "Frag <span class=\"notranslate\" id=\"dnt0\">Jürgen</span>",
+                &protected
+            )
+            .unwrap(),
+            "Frag Jürgen"
+        );
+
+        // Patterns see the text, not its HTML
+        let names = Protector::new(&["AT&T".to_string()], &[]).unwrap();
+        assert_eq!(
+            names.mask("Call AT&amp;T &lt;now&gt;", true),
+            (
+                "Call <span class=\"notranslate\" id=\"dnt0\">AT&amp;T</span> &lt;now&gt;"
+                    .to_string(),
+                vec!["AT&amp;T".to_string()]
+            )
+        );
+
+        // Decimals aren't versions
+        let versions = Protector::new(&[], &[ProtectBuiltin::Version]).unwrap();
+        assert_eq!(
+            versions.mask("Pi is 3.14, not 2.0.1-rc.1 or v2.0", false).1,
+            vec!["2.0.1-rc.1", "v2.0"]
+        );
+
+        let unprotected = Protector::new(&[], &[]).unwrap();
+        assert_eq!(unprotected.mask(html, true), (html.to_string(), vec![]));
+        assert!(Protector::new(&["(".to_string()], &[]).is_err());
+    }
+}
@@ -0,0 +1,350 @@
+use crate::cue_text::{self, CueToken};
+use crate::layout;
+use crate::vtt::{Vtt, VttBlock, VttNonCueBlock};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Limits used when re-timing cues.
+#[derive(Debug, Clone)]
+pub struct RetimeOptions {
+    pub max_line_chars: usize,
+    pub max_lines: usize,
+    pub max_chars_per_second: f64,
+    /// Cues with fewer characters than this are merged into a neighbouring cue where possible.
+    pub min_chars: usize,
+    pub min_duration_ms: u64,
+    pub min_gap_ms: u64,
+}
+
+impl Default for RetimeOptions {
+    fn default() -> Self {
+        RetimeOptions {
+            max_line_chars: 42,
+            max_lines: 2,
+            max_chars_per_second: 17.0,
+            min_chars: 5,
+            min_duration_ms: 1000,
+            min_gap_ms: 80,
+        }
+    }
+}
+
+/// Cues further apart than this are never merged.
+static MAX_MERGE_GAP_MS: u64 = 1000;
+
+/// Characters which make a good place to split a cue when they end a word.
+static BREAK_PUNCTUATION: [char; 12] =
+    [',', ';', ':', '.', '?', '!', '…', '،', '؛', '؟', '。', '，'];
+
+/// Re-time the cues of a translated VTT: split cues with too much text to fit on screen, merge cues left with almost no text, and adjust the timings to give enough time to read each cue.
+///
+/// The cues in the returned VTT are given new identifiers, numbered from 1.
+pub fn retime(vtt: &Vtt, options: &RetimeOptions) -> Vtt {
+    // Split overloaded cues, remembering which original cue each new one came from
+    let mut cues: Vec<(usize, VttBlock)> = vec![];
+    for (block_num, block) in vtt.blocks.iter().enumerate() {
+        cues.extend(split(block, options).into_iter().map(|b| (block_num, b)));
+    }
+
+    let mut cues = merge(cues, options);
+    adjust_timings(&mut cues, options);
+
+    let non_cue_blocks = vtt
+        .non_cue_blocks
+        .iter()
+        .map(|b| VttNonCueBlock {
+            before_block: cues
+                .iter()
+                .position(|(block_num, _)| *block_num >= b.before_block)
+                .unwrap_or(cues.len()),
+            ..b.clone()
+        })
+        .collect();
+    let blocks = cues
+        .into_iter()
+        .enumerate()
+        .map(|(n, (_, block))| VttBlock {
+            id: Some((n + 1).to_string()),
+            ..block
+        })
+        .collect();
+
+    Vtt {
+        header_lines: vtt.header_lines.clone(),
+        blocks,
+        non_cue_blocks,
+    }
+}
+
+fn plain_text(block: &VttBlock) -> String {
+    cue_text::plain_text(&cue_text::tokenize(&block.text_lines.join(" ")))
+}
+
+fn chars(block: &VttBlock) -> usize {
+    plain_text(block).trim().graphemes(true).count()
+}
+
+/// The speakers of the cue, from its voice tags.
+fn voices(block: &VttBlock) -> Vec<String> {
+    let mut voices: Vec<String> = cue_text::tokenize(&block.text_lines.join("\n"))
+        .into_iter()
+        .filter_map(|token| match token {
+            CueToken::StartTag(tag) if tag.name == "v" => tag.annotation,
+            _ => None,
+        })
+        .collect();
+    voices.sort();
+    voices.dedup();
+    voices
+}
+
+/// Split a cue into as many cues as are needed to fit its text on screen, interpolating their timings.
+fn split(block: &VttBlock, options: &RetimeOptions) -> Vec<VttBlock> {
+    let words = cue_text::words(&cue_text::tokenize(&block.text_lines.join("\n")));
+    let widths: Vec<usize> = words
+        .iter()
+        .map(|w| cue_text::display_width(&w.text))
+        .collect();
+    let capacity = options.max_line_chars * options.max_lines;
+    let total_width = widths.iter().sum::<usize>() + widths.len().saturating_sub(1);
+    if total_width <= capacity {
+        return vec![block.clone()];
+    }
+
+    // Prefer to split after punctuation
+    let break_cost = |word_num: usize| {
+        if words[word_num - 1].text.ends_with(BREAK_PUNCTUATION) {
+            0
+        } else {
+            capacity * capacity / 4
+        }
+    };
+    let breaks = (2..=words.len())
+        .find_map(|parts| layout::balanced_breaks(&widths, parts, capacity, break_cost))
+        .unwrap_or_else(|| (1..words.len()).collect());
+
+    let mut groups: Vec<Vec<CueToken>> = vec![vec![]];
+    let mut group_widths = vec![0];
+    for (word_num, word) in words.into_iter().enumerate() {
+        if breaks.contains(&word_num) {
+            groups.push(vec![]);
+            group_widths.push(0);
+        }
+        let group = groups.last_mut().unwrap();
+        if !group.is_empty() {
+            group.push(CueToken::Text(" ".to_string()));
+        }
+        group.extend(word.tokens);
+        *group_widths.last_mut().unwrap() += widths[word_num] + 1;
+    }
+
+    // Share the cue's time between the new cues in proportion to the length of their text
+    let start = block.start.as_millis();
+    let duration = block.end.as_millis() - start;
+    let total_width = group_widths.iter().sum::<usize>() as u64;
+    let mut width_so_far = 0;
+    cue_text::serialize_balanced(&groups)
+        .into_iter()
+        .zip(group_widths)
+        .map(|(text, width)| {
+            let part_start = start + duration * width_so_far / total_width;
+            width_so_far += width as u64;
+            let part_end = start + duration * width_so_far / total_width;
+            VttBlock {
+                id: None,
+                start: block.start.with_millis(part_start),
+                end: block.end.with_millis(part_end),
+                settings: block.settings.clone(),
+                text_lines: layout::wrap(&[text], options.max_line_chars),
+            }
+        })
+        .collect()
+}
+
+fn can_merge(first: &VttBlock, second: &VttBlock, options: &RetimeOptions) -> bool {
+    let first_voices = voices(first);
+    let second_voices = voices(second);
+    let first_text = plain_text(first);
+    let second_text = plain_text(second);
+    second.start >= first.end
+        && second.start.as_millis() - first.end.as_millis() <= MAX_MERGE_GAP_MS
+        && first.settings == second.settings
+        && (first_voices == second_voices || first_voices.is_empty() || second_voices.is_empty())
+        && cue_text::display_width(first_text.trim())
+            + 1
+            + cue_text::display_width(second_text.trim())
+            <= options.max_line_chars * options.max_lines
+}
+
+fn join(first: &VttBlock, second: &VttBlock, options: &RetimeOptions) -> VttBlock {
+    let text = format!(
+        "{} {}",
+        first.text_lines.join("\n"),
+        second.text_lines.join("\n")
+    );
+    VttBlock {
+        id: None,
+        start: first.start,
+        end: second.end,
+        settings: first.settings.clone(),
+        text_lines: layout::wrap(&[text.trim().to_string()], options.max_line_chars),
+    }
+}
+
+/// Merge cues with almost no text into the previous cue, or failing that the next one. Cues with no text at all which can't be merged are dropped.
+fn merge(cues: Vec<(usize, VttBlock)>, options: &RetimeOptions) -> Vec<(usize, VttBlock)> {
+    let mut merged: Vec<(usize, VttBlock)> = vec![];
+    let mut pending: Option<(usize, VttBlock)> = None;
+
+    for (mut block_num, mut cue) in cues {
+        // Prepend any short cue which couldn't be merged into its predecessor
+        if let Some((pending_block_num, pending_cue)) = pending.take() {
+            if can_merge(&pending_cue, &cue, options) {
+                cue = join(&pending_cue, &cue, options);
+                block_num = pending_block_num;
+            } else {
+                merged.push((pending_block_num, pending_cue));
+            }
+        }
+
+        if chars(&cue) < options.min_chars {
+            if let Some((_, previous)) = merged.last_mut() {
+                if can_merge(previous, &cue, options) {
+                    *previous = join(previous, &cue, options);
+                    continue;
+                }
+            }
+            pending = Some((block_num, cue));
+        } else {
+            merged.push((block_num, cue));
+        }
+    }
+    merged.extend(pending);
+    merged.retain(|(_, cue)| chars(cue) > 0);
+    merged
+}
+
+/// Extend cues to their minimum duration and reading time, and trim them to leave a minimum gap before the next cue.
+fn adjust_timings(cues: &mut [(usize, VttBlock)], options: &RetimeOptions) {
+    for i in 0..cues.len() {
+        let next_start = cues.get(i + 1).map(|(_, next)| next.start.as_millis());
+        let cue = &mut cues[i].1;
+        let start = cue.start.as_millis();
+        let mut end = cue.end.as_millis();
+
+        // Cues which overlap the next one are left alone, as the overlap is presumably intended
+        let latest_end = match next_start {
+            Some(next_start) if next_start < end => continue,
+            Some(next_start) => next_start.saturating_sub(options.min_gap_ms),
+            None => u64::MAX,
+        };
+
+        let reading_time =
+            (chars(cue) as f64 * 1000.0 / options.max_chars_per_second).ceil() as u64;
+        let wanted_duration = reading_time.max(options.min_duration_ms);
+        if end - start < wanted_duration {
+            end = end.max((start + wanted_duration).min(latest_end));
+        }
+        if end > latest_end {
+            end = latest_end.max(start + 1);
+        }
+        cue.end = cue.end.with_millis(end);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cues(vtt: &Vtt) -> Vec<(String, String, Vec<String>)> {
+        vtt.blocks
+            .iter()
+            .map(|b| {
+                (
+                    b.id.clone().unwrap_or_default(),
+                    format!("{} --> {}", b.start, b.end),
+                    b.text_lines.clone(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_split_overloaded_cue() {
+        let vtt = Vtt::parse_str(
+            "WEBVTT\n\nNOTE before\n\na\n00:00.000 --> 00:08.000\n<v Fred>This translation is much longer than the original text was, so it needs to be split across two cues.</v>\n",
+        )
+        .unwrap();
+        let retimed = retime(&vtt, &RetimeOptions::default());
+        assert_eq!(
+            cues(&retimed),
+            vec![
+                (
+                    "1".to_string(),
+                    "00:00.000 --> 00:04.672".to_string(),
+                    vec![
+                        "<v Fred>This translation is much longer".to_string(),
+                        "than the original text was,</v>".to_string()
+                    ]
+                ),
+                (
+                    "2".to_string(),
+                    "00:04.752 --> 00:08.000".to_string(),
+                    vec!["<v Fred>so it needs to be split across two cues.</v>".to_string()]
+                ),
+            ]
+        );
+        assert_eq!(retimed.non_cue_blocks[0].before_block, 0);
+    }
+
+    #[test]
+    fn test_merge_near_empty_cues() {
+        let vtt = Vtt::parse_str(
+            "WEBVTT\n\n00:00.000 --> 00:02.000\nThe first cue\n\n00:02.000 --> 00:03.000\nyes\n\n00:03.000 --> 00:04.000\n\n\n00:10.000 --> 00:11.000\nOK\n\n00:11.000 --> 00:13.000\nThe last cue\n\nNOTE end\n",
+        )
+        .unwrap();
+        let retimed = retime(
+            &vtt,
+            &RetimeOptions {
+                min_duration_ms: 0,
+                min_gap_ms: 0,
+                ..RetimeOptions::default()
+            },
+        );
+        assert_eq!(
+            cues(&retimed),
+            vec![
+                (
+                    "1".to_string(),
+                    "00:00.000 --> 00:04.000".to_string(),
+                    vec!["The first cue yes".to_string()]
+                ),
+                (
+                    "2".to_string(),
+                    "00:10.000 --> 00:13.000".to_string(),
+                    vec!["OK The last cue".to_string()]
+                ),
+            ]
+        );
+        assert_eq!(retimed.non_cue_blocks[0].before_block, 2);
+    }
+
+    #[test]
+    fn test_minimum_duration_and_gap() {
+        let vtt = Vtt::parse_str(
+            "WEBVTT\n\n00:00.000 --> 00:00.500\nA short cue\n\n00:00.550 --> 00:01.000\nA cue needing more reading time\n\n00:05.000 --> 00:05.200\nThe last cue\n",
+        )
+        .unwrap();
+        let retimed = retime(&vtt, &RetimeOptions::default());
+        assert_eq!(
+            cues(&retimed)
+                .into_iter()
+                .map(|(_, timing, _)| timing)
+                .collect::<Vec<_>>(),
+            vec![
+                "00:00.000 --> 00:00.470",
+                "00:00.550 --> 00:02.374",
+                "00:05.000 --> 00:06.000"
+            ]
+        );
+    }
+}
@@ -0,0 +1,237 @@
+use anyhow::{Context, Result};
+use futures::Future;
+use rand::Rng;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+/// How to retry requests which fail because of throttling or transient errors.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts at each request, including the first.
+    pub max_attempts: u32,
+    /// Delay before the first retry, which doubles for each further retry.
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before retrying after `attempt` attempts, where `jitter` (between 0 and 1) picks a delay between half and all of the exponential backoff.
+    fn backoff_delay(&self, attempt: u32, jitter: f64) -> Duration {
+        let backoff = self
+            .initial_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+        backoff.mul_f64(0.5 + jitter.clamp(0.0, 1.0) / 2.0)
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ErrorResponseError {
+    code: serde_json::Value,
+    message: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ErrorResponse {
+    error: ErrorResponseError,
+}
+
+/// An error response from an API, which was given up on. Callers can downcast to it to check the status.
+#[derive(Debug)]
+pub struct ResponseError {
+    pub status: StatusCode,
+    message: String,
+}
+
+impl fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ResponseError {}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status.is_server_error()
+}
+
+/// The delay requested by a `Retry-After` header, given as either a number of seconds or an HTTP date.
+fn retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// Describe an error response, including the code and message from its body if it's in the Azure error format.
+fn describe_error(status: StatusCode, body: &str) -> String {
+    match serde_json::from_str::<ErrorResponse>(body) {
+        Ok(ErrorResponse { error }) => {
+            let code = match error.code {
+                serde_json::Value::String(code) => code,
+                code => code.to_string(),
+            };
+            format!("{} (error {}: {})", status, code, error.message)
+        }
+        Err(_) if body.trim().is_empty() => status.to_string(),
+        Err(_) => format!("{} ({})", status, body.trim()),
+    }
+}
+
+/// Send a request, retrying with exponential backoff if it is throttled, fails with a server error or can't be sent.
+///
+/// `request` is called to build the request afresh for each attempt. `description` names the API for error messages.
+pub async fn send<F>(policy: &RetryPolicy, description: &str, request: F) -> Result<Response>
+where
+    F: Fn() -> RequestBuilder,
+{
+    send_with(policy, description, || std::future::ready(Ok(request()))).await
+}
+
+/// Send a request as `send` does, building it asynchronously for each attempt, e.g. to add an access token which may need refreshing.
+pub async fn send_with<F, Fut>(
+    policy: &RetryPolicy,
+    description: &str,
+    request: F,
+) -> Result<Response>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<RequestBuilder>>,
+{
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let last_attempt = attempt >= policy.max_attempts;
+        let backoff_delay = policy.backoff_delay(attempt, rand::thread_rng().gen());
+
+        let delay = match request().await?.send().await {
+            Ok(resp) if resp.status().is_success() => return Ok(resp),
+            Ok(resp) => {
+                let status = resp.status();
+                let requested_delay = resp
+                    .headers()
+                    .get(reqwest::header::RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(retry_after);
+                if last_attempt || !is_retryable(status) {
+                    let body = resp.text().await.unwrap_or_default();
+                    let attempts = if attempt > 1 {
+                        format!(" after {} attempts", attempt)
+                    } else {
+                        String::new()
+                    };
+                    return Err(ResponseError {
+                        status,
+                        message: format!(
+                            "{} returned error response code {}{}",
+                            description,
+                            describe_error(status, &body),
+                            attempts
+                        ),
+                    }
+                    .into());
+                }
+                // Don't let the server stall the run for longer than the policy allows
+                requested_delay.map_or(backoff_delay, |d| d.min(policy.max_delay))
+            }
+            Err(e) if last_attempt || !(e.is_connect() || e.is_timeout() || e.is_request()) => {
+                return Err(e).with_context(|| format!("Error calling the {}", description));
+            }
+            Err(_) => backoff_delay,
+        };
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_delay() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.backoff_delay(1, 1.0), Duration::from_secs(1));
+        assert_eq!(policy.backoff_delay(3, 1.0), Duration::from_secs(4));
+        assert_eq!(policy.backoff_delay(3, 0.0), Duration::from_secs(2));
+        assert_eq!(policy.backoff_delay(10, 1.0), Duration::from_secs(60));
+        assert_eq!(policy.backoff_delay(100, 0.5), Duration::from_secs(45));
+    }
+
+    #[test]
+    fn test_retry_after() {
+        assert_eq!(retry_after("17"), Some(Duration::from_secs(17)));
+        assert_eq!(
+            retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(retry_after("soon"), None);
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_is_limited() {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "3600"))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+
+        let policy = RetryPolicy {
+            max_delay: Duration::from_millis(10),
+            ..RetryPolicy::default()
+        };
+        let client = reqwest::Client::new();
+        let resp = tokio::time::timeout(
+            Duration::from_secs(5),
+            send(&policy, "Test API", || client.get(server.uri())),
+        )
+        .await
+        .expect("Retry-After should be limited to the maximum delay")
+        .unwrap();
+        assert!(resp.status().is_success());
+    }
+
+    #[test]
+    fn test_describe_error() {
+        assert_eq!(
+            describe_error(
+                StatusCode::TOO_MANY_REQUESTS,
+                r#"{"error":{"code":429001,"message":"The server rejected the request because the client has exceeded request limits."}}"#
+            ),
+            "429 Too Many Requests (error 429001: The server rejected the request because the client has exceeded request limits.)"
+        );
+        assert_eq!(
+            describe_error(StatusCode::BAD_GATEWAY, "upstream failed\n"),
+            "502 Bad Gateway (upstream failed)"
+        );
+        assert_eq!(
+            describe_error(StatusCode::SERVICE_UNAVAILABLE, ""),
+            "503 Service Unavailable"
+        );
+    }
+}
@@ -0,0 +1,357 @@
+/// Terminators which end a sentence wherever they appear, as they are used in scripts which don't separate sentences with spaces.
+static UNSPACED_TERMINATORS: [char; 6] = ['。', '！', '？', '؟', '।', '॥'];
+
+/// Terminators which end a sentence only when followed by whitespace.
+static SPACED_TERMINATORS: [char; 7] = ['.', '?', '!', '…', '‼', '⁇', '⁉'];
+
+/// Closing punctuation which belongs to the sentence before it, e.g. the quote in `He said "Hi."`.
+static CLOSING_PUNCTUATION: [char; 11] =
+    ['"', '\'', '”', '’', ')', ']', '}', '»', '」', '』', '）'];
+
+static ABBREVIATIONS_EN: [&str; 22] = [
+    "mr", "mrs", "ms", "dr", "prof", "sr", "jr", "st", "vs", "e.g", "i.e", "eg", "ie", "approx",
+    "dept", "inc", "ltd", "co", "corp", "vol", "fig", "cf",
+];
+/// Abbreviations which are also words, e.g. "No.", so are only abbreviations when a number follows, as in "No. 5".
+static NUMBER_ABBREVIATIONS_EN: [&str; 2] = ["no", "nos"];
+
+/// Capitalised words which often start a sentence, so don't show that the capital letter before them is an initial, e.g. "plan B. Then".
+static SENTENCE_STARTS_EN: [&str; 32] = [
+    "a", "after", "and", "but", "did", "do", "he", "how", "i", "if", "in", "is", "it", "maybe",
+    "no", "now", "ok", "okay", "oh", "she", "so", "that", "the", "then", "there", "this", "we",
+    "well", "what", "when", "why", "you",
+];
+static ABBREVIATIONS_DE: [&str; 16] = [
+    "z.b", "bzw", "ca", "nr", "dr", "prof", "hr", "fr", "vgl", "ggf", "evtl", "d.h", "u.a", "s",
+    "inkl", "str",
+];
+static ABBREVIATIONS_FR: [&str; 9] = ["m", "mm", "mme", "mlle", "dr", "p.ex", "cf", "av", "n°"];
+static ABBREVIATIONS_ES: [&str; 10] = [
+    "sr", "sra", "srta", "dr", "dra", "p.ej", "ud", "uds", "núm", "pág",
+];
+
+/// Finds the ends of sentences in text, using the punctuation conventions of the source language.
+#[derive(Debug, Clone)]
+pub struct SentenceSegmenter {
+    abbreviations: Vec<&'static str>,
+    number_abbreviations: Vec<&'static str>,
+}
+
+impl SentenceSegmenter {
+    /// Create a segmenter for the given language code, or for English punctuation conventions if the language isn't known.
+    pub fn new(language: Option<&str>) -> SentenceSegmenter {
+        let primary_language = language.unwrap_or("").split('-').next().unwrap_or("");
+        let abbreviations = match primary_language {
+            "de" => ABBREVIATIONS_DE.to_vec(),
+            "fr" => ABBREVIATIONS_FR.to_vec(),
+            "es" => ABBREVIATIONS_ES.to_vec(),
+            _ => ABBREVIATIONS_EN.to_vec(),
+        };
+        let number_abbreviations = match primary_language {
+            "de" | "fr" | "es" => vec![],
+            _ => NUMBER_ABBREVIATIONS_EN.to_vec(),
+        };
+        SentenceSegmenter {
+            abbreviations,
+            number_abbreviations,
+        }
+    }
+
+    /// Byte offsets in `text` at which sentences end.
+    pub fn sentence_ends(&self, text: &str) -> Vec<usize> {
+        let chars: Vec<(usize, char)> = text.char_indices().collect();
+        let mut ends = vec![];
+        let mut i = 0;
+
+        while i < chars.len() {
+            let (start, c) = chars[i];
+            if !UNSPACED_TERMINATORS.contains(&c) && !SPACED_TERMINATORS.contains(&c) {
+                i += 1;
+                continue;
+            }
+
+            // Treat a run of terminators (e.g. "?!" or "...") and any closing punctuation as one
+            let mut end = i;
+            while end + 1 < chars.len()
+                && (UNSPACED_TERMINATORS.contains(&chars[end + 1].1)
+                    || SPACED_TERMINATORS.contains(&chars[end + 1].1))
+            {
+                end += 1;
+            }
+            while end + 1 < chars.len() && CLOSING_PUNCTUATION.contains(&chars[end + 1].1) {
+                end += 1;
+            }
+            let terminators: String = chars[i..=end].iter().map(|(_, c)| c).collect();
+            let next = chars.get(end + 1).map(|(_, c)| *c);
+            let offset = chars.get(end + 1).map_or(text.len(), |(o, _)| *o);
+            i = end + 1;
+
+            let is_end = if terminators
+                .chars()
+                .any(|c| UNSPACED_TERMINATORS.contains(&c))
+            {
+                true
+            } else if next.is_some_and(|c| !c.is_whitespace()) {
+                // e.g. decimals ("3.5"), URLs ("example.com") and "?!" inside words
+                false
+            } else if self.next_word_is_lowercase(&text[offset..]) {
+                // e.g. "approx. five" or "wait... what"
+                false
+            } else if terminators == "." {
+                !self.is_abbreviation(&text[..start], &text[offset..])
+            } else {
+                true
+            };
+            if is_end {
+                ends.push(offset);
+            }
+        }
+        ends
+    }
+
+    fn next_word_is_lowercase(&self, rest: &str) -> bool {
+        rest.trim_start()
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_lowercase())
+    }
+
+    /// Whether the word at the end of `text` (which is followed by a full stop and then `rest`) is an abbreviation or initial.
+    fn is_abbreviation(&self, text: &str, rest: &str) -> bool {
+        let mut words = text
+            .rsplit(char::is_whitespace)
+            .map(|w| w.trim_start_matches(|c: char| !c.is_alphanumeric()));
+        let word = words.next().unwrap_or("");
+        let previous_word = words.find(|w| !w.is_empty());
+        let next_word = rest
+            .split_whitespace()
+            .next()
+            .unwrap_or("")
+            .trim_start_matches(|c: char| !c.is_alphanumeric());
+        let lowercase_word = word.to_lowercase();
+        if self.abbreviations.contains(&lowercase_word.as_str()) {
+            return true;
+        }
+        if self.number_abbreviations.contains(&lowercase_word.as_str()) {
+            return next_word.starts_with(|c: char| c.is_ascii_digit());
+        }
+
+        // A capital letter is an initial when followed by another, e.g. "J. R.", or by a surname, e.g. "F. Kennedy"
+        let is_capital_letter = word.chars().count() == 1 && word.chars().all(char::is_uppercase);
+        if !is_capital_letter {
+            return false;
+        }
+        let next_is_initial = next_word.chars().count() == 2
+            && next_word.starts_with(char::is_uppercase)
+            && next_word.ends_with('.');
+        let next_is_name = next_word.starts_with(char::is_uppercase)
+            && !SENTENCE_STARTS_EN.contains(
+                &next_word
+                    .trim_end_matches(|c: char| !c.is_alphanumeric())
+                    .to_lowercase()
+                    .as_str(),
+            )
+            && !previous_word.is_some_and(|w| w.starts_with(char::is_lowercase));
+        next_is_initial || next_is_name
+    }
+}
+
+/// Split `text` into its body and the sentence terminators and closing punctuation at its end.
+fn split_terminator(text: &str) -> (&str, &str, &str) {
+    let without_closing = text.trim_end_matches(|c| CLOSING_PUNCTUATION.contains(&c));
+    let body = without_closing
+        .trim_end_matches(|c| UNSPACED_TERMINATORS.contains(&c) || SPACED_TERMINATORS.contains(&c));
+    (
+        body,
+        &without_closing[body.len()..],
+        &text[without_closing.len()..],
+    )
+}
+
+/// Convert sentence terminators to the conventions of the script used by the target language.
+fn convert_terminator(terminator: &str, target_language: &str) -> String {
+    // First normalise to Latin punctuation
+    let terminator = terminator
+        .replace("...", "…")
+        .chars()
+        .map(|c| match c {
+            '。' | '।' | '॥' | '։' | '።' | '။' | '។' => ".".to_string(),
+            '？' | '؟' | '\u{037E}' => "?".to_string(),
+            '！' => "!".to_string(),
+            '‼' => "!!".to_string(),
+            '⁇' => "??".to_string(),
+            '⁉' => "!?".to_string(),
+            c => c.to_string(),
+        })
+        .collect::<String>();
+
+    let primary_language = target_language.split('-').next().unwrap_or("");
+    let script_terminator = |c: char| -> Option<&str> {
+        match (primary_language, c) {
+            ("zh" | "ja" | "yue" | "lzh", '.') => Some("。"),
+            ("zh" | "ja" | "yue" | "lzh", '?') => Some("？"),
+            ("zh" | "ja" | "yue" | "lzh", '!') => Some("！"),
+            ("ar" | "fa" | "ur" | "ps" | "prs" | "ckb" | "ug" | "sd", '?') => Some("؟"),
+            ("hi" | "mr" | "ne" | "bn" | "pa" | "as" | "sa" | "mai", '.') => Some("।"),
+            ("el", '?') => Some("\u{037E}"),
+            ("hy", '.') => Some("։"),
+            ("am" | "ti", '.') => Some("።"),
+            ("my", '.') => Some("။"),
+            ("km", '.') => Some("។"),
+            ("th" | "lo", '.') => Some(""),
+            _ => None,
+        }
+    };
+    terminator
+        .chars()
+        .map(|c| script_terminator(c).map_or(c.to_string(), String::from))
+        .collect()
+}
+
+/// Make the end of a translated sentence mirror the end of the source sentence, converting its terminator (if any) to the target language's script.
+///
+/// `translation` is HTML, so any tags at its end are skipped over.
+pub fn mirror_terminator(source: &str, translation: &str, target_language: &str) -> String {
+    let (_, source_terminator, _) = split_terminator(source.trim_end());
+
+    // Skip over any whitespace and tags at the end of the translation
+    let mut text_end = translation.len();
+    loop {
+        let trimmed = translation[..text_end].trim_end();
+        match trimmed.strip_suffix('>').and_then(|t| t.rfind('<')) {
+            Some(tag_start) => text_end = tag_start,
+            None => {
+                text_end = trimmed.len();
+                break;
+            }
+        }
+    }
+
+    let (body, _, closing) = split_terminator(&translation[..text_end]);
+    format!(
+        "{}{}{}{}",
+        body,
+        convert_terminator(source_terminator, target_language),
+        closing,
+        &translation[text_end..]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(segmenter: &SentenceSegmenter, text: &str) -> Vec<String> {
+        let mut sentences = vec![];
+        let mut start = 0;
+        for end in segmenter.sentence_ends(text) {
+            sentences.push(text[start..end].trim().to_string());
+            start = end;
+        }
+        if start < text.len() {
+            sentences.push(text[start..].trim().to_string());
+        }
+        sentences
+    }
+
+    #[test]
+    fn test_questions_and_exclamations() {
+        let segmenter = SentenceSegmenter::new(None);
+        assert_eq!(
+            split(&segmenter, "Is it? Yes! Really?! \"Great.\" OK"),
+            vec!["Is it?", "Yes!", "Really?!", "\"Great.\"", "OK"]
+        );
+    }
+
+    #[test]
+    fn test_ellipses() {
+        let segmenter = SentenceSegmenter::new(None);
+        assert_eq!(
+            split(&segmenter, "Well... maybe. Wait… No."),
+            vec!["Well... maybe.", "Wait…", "No."]
+        );
+    }
+
+    #[test]
+    fn test_abbreviations_decimals_and_urls() {
+        let segmenter = SentenceSegmenter::new(None);
+        assert_eq!(
+            split(
+                &segmenter,
+                "Ask Dr. Smith, e.g. about the 3.5% rise at www.example.com. J. R. Hartley agreed."
+            ),
+            vec![
+                "Ask Dr. Smith, e.g. about the 3.5% rise at www.example.com.",
+                "J. R. Hartley agreed."
+            ]
+        );
+    }
+
+    #[test]
+    fn test_words_which_look_like_abbreviations() {
+        let segmenter = SentenceSegmenter::new(Some("en"));
+        assert_eq!(
+            split(&segmenter, "No. I don't think so. See No. 5 instead."),
+            vec!["No.", "I don't think so.", "See No. 5 instead."]
+        );
+        assert_eq!(
+            split(
+                &segmenter,
+                "We chose plan B. Then it failed. Ask John F. Kennedy."
+            ),
+            vec![
+                "We chose plan B.",
+                "Then it failed.",
+                "Ask John F. Kennedy."
+            ]
+        );
+    }
+
+    #[test]
+    fn test_language_specific_abbreviations() {
+        let segmenter = SentenceSegmenter::new(Some("de"));
+        assert_eq!(
+            split(&segmenter, "Das ist z.B. Gut. Ja."),
+            vec!["Das ist z.B. Gut.", "Ja."]
+        );
+    }
+
+    #[test]
+    fn test_unspaced_terminators() {
+        let segmenter = SentenceSegmenter::new(None);
+        assert_eq!(
+            split(&segmenter, "你好。你好吗？很好！"),
+            vec!["你好。", "你好吗？", "很好！"]
+        );
+        assert_eq!(
+            split(&segmenter, "حالت چطوره؟ خوبم."),
+            vec!["حالت چطوره؟", "خوبم."]
+        );
+    }
+
+    #[test]
+    fn test_mirror_terminator() {
+        assert_eq!(mirror_terminator("Hello.", "Bonjour", "fr"), "Bonjour.");
+        assert_eq!(
+            mirror_terminator("Really?", "Vraiment ?", "fr"),
+            "Vraiment ?"
+        );
+        assert_eq!(mirror_terminator("Really?", "واقعا؟.", "fa"), "واقعا؟");
+        assert_eq!(mirror_terminator("Really?", "واقعا?", "fa"), "واقعا؟");
+        assert_eq!(mirror_terminator("Hello.", "你好", "zh-Hans"), "你好。");
+        assert_eq!(mirror_terminator("Wait...", "待って", "ja"), "待って…");
+        assert_eq!(mirror_terminator("And so", "Et donc.", "fr"), "Et donc");
+        assert_eq!(
+            mirror_terminator("你好吗？", "How are you", "en"),
+            "How are you?"
+        );
+        assert_eq!(
+            mirror_terminator("He said \"hi.\"", "Er sagte \"hallo\" ", "de"),
+            "Er sagte \"hallo.\" "
+        );
+        assert_eq!(
+            mirror_terminator("Stop!", "<span id=\"0\">Arrête</span>", "fr"),
+            "<span id=\"0\">Arrête!</span>"
+        );
+    }
+}
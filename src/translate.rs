@@ -1,64 +1,118 @@
-use anyhow::{anyhow, Context, Result};
-use clap::ValueEnum;
-use std::collections::HashMap;
-use uuid::Uuid;
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use futures::Future;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
 
-static DEFAULT_ENDPOINT: &str = "https://api.cognitive.microsofttranslator.com";
-static TRANSLATE_PATH: &str = "/translate";
-static LANGUAGES_PATH: &str = "/languages";
-static DEFAULT_VERSION: &str = "3.0";
+mod azure;
+mod caching;
+mod deepl;
+mod google;
+mod libretranslate;
+mod metered;
+mod openai;
+mod pseudo;
 
-// @@TODO Instead of hardcoding this enum, dynamically call the /languages?scope=translation endpoint to get the full list of supported languages
+pub use azure::{AzureAuth, AzureCloud, TranslationClient};
+pub use caching::CachingTranslator;
+pub use deepl::DeepLClient;
+pub use google::GoogleClient;
+pub use libretranslate::LibreTranslateClient;
+pub use metered::{billed_chars, MeteredTranslator, Usage};
+pub use openai::OpenAiClient;
+pub use pseudo::PseudoTranslator;
+
+/// How long the list of supported languages is cached on disk before being fetched again.
+static LANGUAGES_CACHE_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Languages written right to left, unless their tag gives a script which isn't.
+static RTL_LANGUAGES: [&str; 16] = [
+    "ar", "arc", "ckb", "dv", "fa", "he", "iw", "ks", "ku-Arab", "prs", "ps", "sd", "syr", "ug",
+    "ur", "yi",
+];
+static RTL_SCRIPTS: [&str; 5] = ["Arab", "Hebr", "Syrc", "Thaa", "Nkoo"];
+
+/// A BCP-47 language tag, e.g. `fa`, `en-GB` or `zh-Hans`.
 #[derive(
-    Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum, serde::Serialize, serde::Deserialize,
+    Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
 )]
-pub enum Language {
-    #[serde(rename = "en")]
-    En,
-    #[serde(rename = "en-gb")]
-    EnGB,
-    #[serde(rename = "fa")]
-    Fa,
-}
+#[serde(try_from = "String", into = "String")]
+pub struct Language(String);
 
-impl std::fmt::Display for Language {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            serde_json::to_string(&self)
-                .expect("Failed to serialize Language")
-                .replace('"', "")
+impl Language {
+    /// Check that the translation API supports this language, returning it as spelt by the API (tags are case-insensitive).
+    pub fn supported(&self, languages: &BTreeMap<String, LanguageInfo>) -> Result<Language> {
+        if let Some(code) = languages
+            .keys()
+            .find(|code| code.eq_ignore_ascii_case(&self.0))
+        {
+            return Ok(Language(code.clone()));
+        }
+
+        // Suggest a more general language which is supported, e.g. "en" for "en-US"
+        let mut tag = self.0.as_str();
+        while let Some((prefix, _)) = tag.rsplit_once('-') {
+            tag = prefix;
+            if let Some(code) = languages.keys().find(|code| code.eq_ignore_ascii_case(tag)) {
+                bail!(
+                    "Language \"{}\" is not supported by the translation API, did you mean \"{}\"? Use --list-languages to see all supported languages",
+                    self, code
+                );
+            }
+        }
+        bail!(
+            "Language \"{}\" is not supported by the translation API. Use --list-languages to see all supported languages",
+            self
         )
     }
 }
 
-#[derive(Debug, Clone, serde::Serialize)]
-struct TranslateRequestItem {
-    text: String,
+impl FromStr for Language {
+    type Err = anyhow::Error;
+
+    fn from_str(tag: &str) -> Result<Self> {
+        // Language, extended language, script, region and variant subtags
+        static LANGUAGE_TAG_RE: OnceLock<Regex> = OnceLock::new();
+        let language_tag_re = LANGUAGE_TAG_RE.get_or_init(|| {
+            Regex::new(
+                r"^[A-Za-z]{2,3}(-[A-Za-z]{3}){0,3}(-[A-Za-z]{4})?(-[A-Za-z]{2}|-[0-9]{3})?(-[A-Za-z0-9]{5,8}|-[0-9][A-Za-z0-9]{3})*$",
+            )
+            .unwrap()
+        });
+        if !language_tag_re.is_match(tag) {
+            bail!("\"{}\" is not a valid BCP-47 language tag", tag);
+        }
+        Ok(Language(tag.to_string()))
+    }
 }
 
-#[derive(Debug, Clone, Copy, serde::Deserialize)]
-struct TranslateResponseDetectedLanguage {
-    language: Language,
-    score: f32,
+impl TryFrom<String> for Language {
+    type Error = anyhow::Error;
+
+    fn try_from(tag: String) -> Result<Self> {
+        tag.parse()
+    }
 }
 
-#[derive(Debug, Clone, serde::Deserialize)]
-struct TranslateResponseTranslation {
-    #[serde(rename = "to")]
-    _language: Language,
-    text: String,
+impl From<Language> for String {
+    fn from(language: Language) -> Self {
+        language.0
+    }
 }
 
-#[derive(Debug, Clone, serde::Deserialize)]
-struct TranslateResponseItem {
-    #[serde(rename = "detectedLanguage")]
-    detected_language: Option<TranslateResponseDetectedLanguage>,
-    translations: Vec<TranslateResponseTranslation>,
+impl std::fmt::Display for Language {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
 }
 
-#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
 pub enum Direction {
     #[serde(rename = "ltr")]
     Ltr,
@@ -66,152 +120,342 @@ pub enum Direction {
     Rtl,
 }
 
-#[derive(Debug, Clone, serde::Deserialize)]
-struct LanguagesResponseLanguage {
-    #[serde(rename = "name")]
-    _name: String,
+/// The direction a language is written in, for translation APIs which don't say.
+pub(crate) fn language_direction(tag: &str) -> Direction {
+    let mut subtags = tag.split('-');
+    let primary_language = subtags.next().unwrap_or("");
+    let script = subtags.find(|s| s.len() == 4 && s.chars().all(char::is_alphabetic));
+    let is_rtl = match script {
+        Some(script) => RTL_SCRIPTS.iter().any(|s| s.eq_ignore_ascii_case(script)),
+        None => RTL_LANGUAGES
+            .iter()
+            .any(|l| l.eq_ignore_ascii_case(primary_language)),
+    };
+    if is_rtl {
+        Direction::Rtl
+    } else {
+        Direction::Ltr
+    }
+}
+
+/// Put a language tag into the conventional case, e.g. `ZH-HANS` to `zh-Hans`, for translation APIs which use another.
+pub(crate) fn conventional_case(tag: &str) -> String {
+    tag.split('-')
+        .enumerate()
+        .map(|(i, subtag)| match subtag.len() {
+            _ if i == 0 => subtag.to_lowercase(),
+            2 => subtag.to_uppercase(),
+            4 => subtag[..1].to_uppercase() + &subtag[1..].to_lowercase(),
+            _ => subtag.to_lowercase(),
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// A language supported by a translation API.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct LanguageInfo {
+    pub name: String,
     #[serde(rename = "nativeName")]
-    _native_name: String,
+    pub native_name: String,
     #[serde(rename = "dir")]
-    direction: Direction,
+    pub direction: Direction,
 }
 
-#[derive(Debug, Clone, serde::Deserialize)]
-struct LanguagesResponse {
-    translation: HashMap<String, LanguagesResponseLanguage>,
-    //transliteration: Option<serde_json::Value>,
-    //dictionary: Option<serde_json::Value>,
+/// A mapping between a range of characters in the source text and the range of characters it was translated to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlignmentSpan {
+    pub source: Range<usize>,
+    pub target: Range<usize>,
 }
 
-pub struct TranslationClient {
-    endpoint: String,
-    version: String,
-    key: String,
-    region: String,
+#[derive(Debug, Clone, PartialEq)]
+pub struct Translation {
+    pub text: String,
+    /// Word alignment between the source and translated text, if the translation API provided it.
+    pub alignment: Option<Vec<AlignmentSpan>>,
 }
 
-impl TranslationClient {
-    pub fn new(key: String, region: String) -> TranslationClient {
-        TranslationClient {
-            endpoint: DEFAULT_ENDPOINT.to_string(),
-            version: DEFAULT_VERSION.to_string(),
-            key,
-            region,
-        }
+/// A machine translation API.
+///
+/// Segments are HTML, with cue markup as `<span>` tags which must be kept in the translation.
+#[async_trait]
+pub trait Translator: Send + Sync {
+    /// The name of the translation API, for messages.
+    fn name(&self) -> &str;
+
+    /// Identifies the translation API, and the server if it can be self-hosted, for caching its list of languages.
+    fn cache_key(&self) -> String {
+        self.name().to_string()
     }
 
-    async fn translation_languages(&self) -> Result<HashMap<String, LanguagesResponseLanguage>> {
-        let params = vec![
-            ("api-version", self.version.clone()),
-            ("scope", "translation".to_string()),
-        ];
-        let url = reqwest::Url::parse_with_params(
-            &format!("{}{}", self.endpoint, LANGUAGES_PATH),
-            &params,
-        )
-        .with_context(|| "Failed to generate request URL with params")?;
-
-        let client = reqwest::Client::new();
-        let resp = client
-            .get(url)
-            .send()
-            .await
-            .with_context(|| "Error calling the Azure translation API /languages endpoint")?;
-
-        if resp.status() != 200 {
-            return Err(anyhow!(
-                "Azure translation API /languates endpoint returned error response code {}",
-                resp.status()
-            ));
-        };
+    /// Identifies the options which change the API's translations, such as the model, for caching translations.
+    fn options_key(&self) -> String {
+        String::new()
+    }
 
-        let resp_body = resp.json::<LanguagesResponse>().await.unwrap();
+    /// The number of characters the API bills for translating the segments into the language, including any markup added to them before they're sent.
+    fn billed_chars(&self, segments: &[String], _to: &Language) -> Result<usize> {
+        Ok(billed_chars(segments))
+    }
 
-        Ok(resp_body.translation)
+    /// Whether the API leaves the content of `<span class="notranslate">` tags untranslated, so protected text can stay in place rather than be replaced by a placeholder.
+    fn honours_notranslate(&self) -> bool {
+        false
     }
 
-    pub async fn translate(
+    /// The languages which can be translated to and from, keyed by language code.
+    async fn languages(&self) -> Result<BTreeMap<String, LanguageInfo>>;
+
+    /// Detect the language of the segments.
+    async fn detect_language(&self, segments: &[String]) -> Result<Language>;
+
+    /// Translate the segments into each of the target languages, returning the (given or detected) source language and the translations into each target language, in order.
+    async fn translate(
         &self,
-        sentences: Vec<String>,
-        from: Option<Language>,
-        to: Language,
-    ) -> Result<(Language, Direction, Vec<String>)> {
-        let mut params = vec![
-            ("api-version", self.version.clone()),
-            ("to", to.to_string()),
-        ];
-        if let Some(source_language) = from {
-            params.push(("from", source_language.to_string()));
-        }
-        let url = reqwest::Url::parse_with_params(
-            &format!("{}{}", self.endpoint, TRANSLATE_PATH),
-            &params,
-        )
-        .with_context(|| "Failed to generate request URL with params")?;
-        let req_body: Vec<TranslateRequestItem> = sentences
-            .into_iter()
-            .map(|s| TranslateRequestItem { text: s })
-            .collect();
-
-        let client = reqwest::Client::new();
-        let resp = client
-            .post(url)
-            .json(&req_body)
-            .header("Ocp-Apim-Subscription-Key", self.key.clone())
-            .header("Ocp-Apim-Subscription-Region", self.region.clone())
-            .header("X-ClientTraceId", Uuid::new_v4().to_string())
-            .send()
-            .await
-            .with_context(|| "Error calling the Azure translation API")?;
-
-        if resp.status() != 200 {
-            return Err(anyhow!(
-                "Azure translation API returned error response code {}",
-                resp.status()
-            ));
-        };
+        segments: &[String],
+        from: Option<&Language>,
+        to: &[Language],
+    ) -> Result<(Language, Vec<Vec<Translation>>)>;
+}
 
-        let resp_body = resp.json::<Vec<TranslateResponseItem>>().await.unwrap();
+/// The language with the highest total score, where each segment votes for the language detected in it with the confidence of the detection.
+pub(crate) fn most_likely_language(
+    votes: impl IntoIterator<Item = (Language, f32)>,
+) -> Option<Language> {
+    let mut scores: BTreeMap<Language, f32> = BTreeMap::new();
+    for (language, score) in votes {
+        *scores.entry(language).or_default() += score;
+    }
+    scores
+        .into_iter()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(language, _)| language)
+}
 
-        let mut translated_sentences = vec![];
-        let mut detected_language = TranslateResponseDetectedLanguage {
-            language: Language::EnGB,
-            score: 0.0,
-        };
-        if let Some(source_language) = from {
-            detected_language.language = source_language;
-            detected_language.score = 1.0;
+/// Limits on the size of a single request to a translation API.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RequestLimits {
+    pub max_segments: usize,
+    /// Maximum characters in a request, counted in UTF-16 code units as Azure does (which over-counts for other APIs).
+    pub max_chars: usize,
+}
+
+/// Split the segments into batches (as ranges of indexes) which each keep within the limits on a single request.
+///
+/// Some APIs count the characters of each segment once for every target language, given by `chars_multiplier`.
+fn batches(
+    segments: &[String],
+    limits: RequestLimits,
+    chars_multiplier: usize,
+) -> Result<Vec<Range<usize>>> {
+    let mut batches = vec![];
+    let mut batch_start = 0;
+    let mut batch_chars = 0;
+    for (i, segment) in segments.iter().enumerate() {
+        let chars = segment.encode_utf16().count() * chars_multiplier;
+        if chars > limits.max_chars {
+            bail!(
+                "Sentence {} is too long to translate in one request ({} characters)",
+                i + 1,
+                chars
+            );
         }
-        for response_item in resp_body.into_iter() {
-            if response_item
-                .detected_language
-                .unwrap_or(detected_language)
-                .score
-                > detected_language.score
-            {
-                detected_language = response_item.detected_language.unwrap();
+        if i - batch_start == limits.max_segments || batch_chars + chars > limits.max_chars {
+            batches.push(batch_start..i);
+            batch_start = i;
+            batch_chars = 0;
+        }
+        batch_chars += chars;
+    }
+    if batch_start < segments.len() {
+        batches.push(batch_start..segments.len());
+    }
+    Ok(batches)
+}
+
+/// Send the segments to `send_batch` in as many batches as needed to keep within the limits, with up to `max_concurrent_requests` batches at once, and reassemble the results in order.
+///
+/// `send_batch` is given the index of the first segment in the batch, and the batch.
+pub(crate) async fn in_batches<'a, T, F, Fut>(
+    segments: &'a [String],
+    limits: RequestLimits,
+    chars_multiplier: usize,
+    max_concurrent_requests: usize,
+    send_batch: F,
+) -> Result<Vec<T>>
+where
+    F: Fn(usize, &'a [String]) -> Fut,
+    Fut: Future<Output = Result<Vec<T>>>,
+{
+    let batches = batches(segments, limits, chars_multiplier)?;
+    let num_batches = batches.len();
+    let results: Vec<Vec<T>> = stream::iter(batches)
+        .enumerate()
+        .map(|(batch_num, batch)| {
+            let batch_start = batch.start;
+            let batch = &segments[batch];
+            let response = send_batch(batch_start, batch);
+            async move {
+                let results = response.await.with_context(|| {
+                    format!(
+                        "Failed to translate batch {} of {}",
+                        batch_num + 1,
+                        num_batches
+                    )
+                })?;
+                if results.len() != batch.len() {
+                    bail!(
+                        "Translation API returned {} results for {} sentences",
+                        results.len(),
+                        batch.len()
+                    );
+                }
+                Ok(results)
             }
+        })
+        .buffered(max_concurrent_requests.max(1))
+        .try_collect()
+        .await?;
+    Ok(results.into_iter().flatten().collect())
+}
 
-            // The response always contains a single translation in the language that we asked for
-            assert_eq!(response_item.translations.len(), 1);
-            assert_eq!(response_item.translations[0]._language, to);
-            let mut sentence = response_item.translations[0].text.clone();
+/// Where to cache the list of languages supported by a translation API, following the XDG base directory conventions.
+fn languages_cache_file(translator: &dyn Translator) -> Option<PathBuf> {
+    let cache_dir = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+    let cache_key = translator
+        .cache_key()
+        .to_lowercase()
+        .replace(|c: char| !c.is_alphanumeric(), "-");
+    Some(
+        cache_dir
+            .join("vtt-translate")
+            .join(format!("languages-{}.json", cache_key)),
+    )
+}
 
-            // The translation API doesn't always return full sentences - add a fullstop if it is missing.
-            if !sentence.ends_with('.') {
-                sentence.push('.');
-            }
+fn read_languages_cache(cache_file: &Path) -> Option<BTreeMap<String, LanguageInfo>> {
+    let age = std::fs::metadata(cache_file)
+        .and_then(|m| m.modified())
+        .ok()
+        .and_then(|modified| SystemTime::now().duration_since(modified).ok())?;
+    if age > LANGUAGES_CACHE_LIFETIME {
+        return None;
+    }
+    let contents = std::fs::read_to_string(cache_file).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+fn write_languages_cache(
+    cache_file: &Path,
+    languages: &BTreeMap<String, LanguageInfo>,
+) -> Result<()> {
+    if let Some(cache_dir) = cache_file.parent() {
+        std::fs::create_dir_all(cache_dir)?;
+    }
+    std::fs::write(cache_file, serde_json::to_string(languages)?)?;
+    Ok(())
+}
 
-            translated_sentences.push(sentence);
+/// The languages supported by the translation API, which are cached on disk for a day as they rarely change.
+pub async fn supported_languages(
+    translator: &dyn Translator,
+) -> Result<BTreeMap<String, LanguageInfo>> {
+    let cache_file = languages_cache_file(translator);
+    if let Some(languages) = cache_file.as_deref().and_then(read_languages_cache) {
+        return Ok(languages);
+    }
+
+    let languages = translator.languages().await?;
+    if let Some(cache_file) = cache_file {
+        // Failing to cache the list only costs us another request next time
+        let _ = write_languages_cache(&cache_file, &languages);
+    }
+    Ok(languages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn language_info(name: &str) -> LanguageInfo {
+        LanguageInfo {
+            name: name.to_string(),
+            native_name: name.to_string(),
+            direction: Direction::Ltr,
+        }
+    }
+
+    #[test]
+    fn test_parse_language() {
+        for tag in ["fa", "en-GB", "zh-Hans", "sr-Cyrl-RS", "es-419", "tlh-Piqd"] {
+            assert_eq!(tag.parse::<Language>().unwrap().to_string(), tag);
         }
+        for tag in ["", "e", "english", "en_GB", "en-", "fa-IR-"] {
+            assert!(tag.parse::<Language>().is_err(), "{}", tag);
+        }
+    }
+
+    #[test]
+    fn test_supported_language() {
+        let languages = BTreeMap::from([
+            ("en".to_string(), language_info("English")),
+            ("zh-Hans".to_string(), language_info("Chinese Simplified")),
+        ]);
+        let language = |tag: &str| tag.parse::<Language>().unwrap();
+        assert_eq!(
+            language("zh-hans").supported(&languages).unwrap(),
+            language("zh-Hans")
+        );
+        assert!(language("en-GB")
+            .supported(&languages)
+            .unwrap_err()
+            .to_string()
+            .contains("did you mean \"en\"?"));
+        assert!(language("fa").supported(&languages).is_err());
+    }
+
+    #[test]
+    fn test_language_direction_and_case() {
+        assert!(matches!(language_direction("fa"), Direction::Rtl));
+        assert!(matches!(language_direction("AR"), Direction::Rtl));
+        assert!(matches!(language_direction("pa-Arab"), Direction::Rtl));
+        assert!(matches!(language_direction("ku-Latn"), Direction::Ltr));
+        assert!(matches!(language_direction("en-GB"), Direction::Ltr));
+        assert_eq!(conventional_case("ZH-HANS"), "zh-Hans");
+        assert_eq!(conventional_case("EN-gb"), "en-GB");
+        assert_eq!(conventional_case("pt-br"), "pt-BR");
+    }
+
+    #[test]
+    fn test_batches() {
+        let limits = RequestLimits {
+            max_segments: 100,
+            max_chars: 50_000,
+        };
+        let sentences = vec!["a".repeat(10); 250];
+        assert_eq!(
+            batches(&sentences, limits, 1).unwrap(),
+            vec![0..100, 100..200, 200..250]
+        );
+
+        let sentences = vec!["a".repeat(20_000); 5];
+        assert_eq!(
+            batches(&sentences, limits, 1).unwrap(),
+            vec![0..2, 2..4, 4..5]
+        );
+        assert_eq!(
+            batches(&sentences, limits, 2).unwrap(),
+            vec![0..1, 1..2, 2..3, 3..4, 4..5]
+        );
+        assert!(batches(&sentences, limits, 3).is_err());
 
-        let direction = self
-            .translation_languages()
-            .await?
-            .get(&to.to_string())
-            .with_context(|| "Target language not returned by /languages endpoint")?
-            .direction;
+        // Characters outside the Basic Multilingual Plane count twice
+        let sentences = vec!["😀".repeat(10_000); 3];
+        assert_eq!(batches(&sentences, limits, 1).unwrap(), vec![0..2, 2..3]);
 
-        Ok((detected_language.language, direction, translated_sentences))
+        assert!(batches(&[], limits, 1).unwrap().is_empty());
     }
 }
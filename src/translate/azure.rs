@@ -0,0 +1,878 @@
+use super::{
+    in_batches, most_likely_language, AlignmentSpan, Language, LanguageInfo, RequestLimits,
+    Translation, Translator,
+};
+use crate::cue_text::escape_html;
+use crate::glossary::Glossary;
+use crate::retry::{self, RetryPolicy};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::sync::OnceLock;
+use uuid::Uuid;
+
+mod auth;
+
+use auth::Authenticator;
+pub use auth::AzureAuth;
+
+static TRANSLATE_PATH: &str = "/translate";
+static DETECT_PATH: &str = "/detect";
+static LANGUAGES_PATH: &str = "/languages";
+static DEFAULT_VERSION: &str = "3.0";
+
+/// The Azure clouds, each of which has its own global Translator endpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum AzureCloud {
+    #[default]
+    Public,
+    UsGovernment,
+    China,
+}
+
+impl AzureCloud {
+    pub fn endpoint(self) -> &'static str {
+        match self {
+            AzureCloud::Public => "https://api.cognitive.microsofttranslator.com",
+            AzureCloud::UsGovernment => "https://api.cognitive.microsofttranslator.us",
+            AzureCloud::China => "https://api.translator.azure.cn",
+        }
+    }
+
+    /// The scope to request Microsoft Entra ID access tokens for Azure AI services with.
+    pub fn scope(self) -> &'static str {
+        match self {
+            AzureCloud::Public => "https://cognitiveservices.azure.com/.default",
+            AzureCloud::UsGovernment => "https://cognitiveservices.azure.us/.default",
+            AzureCloud::China => "https://cognitiveservices.azure.cn/.default",
+        }
+    }
+
+    /// The Microsoft Entra ID authority host, which issues access tokens.
+    pub fn authority_host(self) -> &'static str {
+        match self {
+            AzureCloud::Public => "https://login.microsoftonline.com",
+            AzureCloud::UsGovernment => "https://login.microsoftonline.us",
+            AzureCloud::China => "https://login.chinacloudapi.cn",
+        }
+    }
+
+    /// The domain of the Azure AI services APIs, including the issueToken endpoints.
+    fn cognitive_services_domain(self) -> &'static str {
+        match self {
+            AzureCloud::Public => "api.cognitive.microsoft.com",
+            AzureCloud::UsGovernment => "api.cognitive.microsoft.us",
+            AzureCloud::China => "api.cognitive.azure.cn",
+        }
+    }
+}
+
+/// Limits on the number of texts, and total characters across all target languages, in a single /translate request.
+static REQUEST_LIMITS: RequestLimits = RequestLimits {
+    max_segments: 100,
+    max_chars: 50_000,
+};
+
+#[derive(Debug, Clone, serde::Serialize)]
+struct TranslateRequestItem {
+    text: String,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct TranslateResponseDetectedLanguage {
+    language: Language,
+    score: f32,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct TranslateResponseAlignment {
+    proj: String,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct TranslateResponseTranslation {
+    #[serde(rename = "to")]
+    language: Language,
+    text: String,
+    alignment: Option<TranslateResponseAlignment>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct TranslateResponseItem {
+    #[serde(rename = "detectedLanguage")]
+    detected_language: Option<TranslateResponseDetectedLanguage>,
+    translations: Vec<TranslateResponseTranslation>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct LanguagesResponse {
+    translation: BTreeMap<String, LanguageInfo>,
+    //transliteration: Option<serde_json::Value>,
+    //dictionary: Option<serde_json::Value>,
+}
+
+/// Parse an alignment projection, e.g. `0:4-0:6 6:10-8:12`, where each pair of inclusive character ranges maps source to target.
+fn parse_alignment(proj: &str) -> Option<Vec<AlignmentSpan>> {
+    let parse_range = |range: &str| -> Option<Range<usize>> {
+        let (start, end) = range.split_once(':')?;
+        let (start, end) = (start.parse::<usize>().ok()?, end.parse::<usize>().ok()?);
+        (start <= end).then_some(start..end + 1)
+    };
+    proj.split_whitespace()
+        .map(|span| {
+            let (source, target) = span.split_once('-')?;
+            Some(AlignmentSpan {
+                source: parse_range(source)?,
+                target: parse_range(target)?,
+            })
+        })
+        .collect()
+}
+
+/// Pick the source language and each target language's translations out of the response items.
+fn collect_translations(
+    response_items: Vec<TranslateResponseItem>,
+    from: Option<&Language>,
+    to: &[Language],
+) -> Result<(Language, Vec<Vec<Translation>>)> {
+    let mut translations = vec![vec![]; to.len()];
+    let mut detected_languages = vec![];
+    for response_item in response_items.into_iter() {
+        if let Some(item_language) = response_item.detected_language {
+            detected_languages.push((item_language.language, item_language.score));
+        }
+
+        // The response contains a translation in each of the languages that we asked for
+        for (language, language_translations) in to.iter().zip(translations.iter_mut()) {
+            let translation = response_item
+                .translations
+                .iter()
+                .find(|t| {
+                    t.language
+                        .to_string()
+                        .eq_ignore_ascii_case(&language.to_string())
+                })
+                .with_context(|| format!("No translation to {} in the response", language))?;
+            language_translations.push(Translation {
+                text: translation.text.clone(),
+                alignment: translation
+                    .alignment
+                    .as_ref()
+                    .and_then(|a| parse_alignment(&a.proj)),
+            });
+        }
+    }
+
+    let source_language = match from {
+        Some(source_language) => source_language.clone(),
+        None => most_likely_language(detected_languages).map_or_else(|| "en".parse(), Ok)?,
+    };
+    Ok((source_language, translations))
+}
+
+/// Glossary terms to mark up with their translations, using Azure's dynamic dictionary.
+struct DictionaryTerms {
+    terms_re: Regex,
+    /// The translation of each term, keyed by the term as HTML.
+    translations: BTreeMap<String, String>,
+}
+
+impl DictionaryTerms {
+    /// Terms are matched case-sensitively, as whole words. Returns `None` if there are no terms.
+    fn new(terms: BTreeMap<&str, &str>) -> Result<Option<DictionaryTerms>> {
+        let translations: BTreeMap<String, String> = terms
+            .into_iter()
+            .map(|(term, translation)| (escape_html(term), translation.to_string()))
+            .collect();
+        // Prefer the longest of overlapping terms
+        let mut terms: Vec<&String> = translations.keys().collect();
+        terms.sort_by_key(|term| std::cmp::Reverse(term.len()));
+        let word_boundary = |c: Option<char>| match c {
+            Some(c) if c.is_alphanumeric() => r"\b",
+            _ => "",
+        };
+        let alternatives: Vec<String> = terms
+            .iter()
+            .map(|term| {
+                format!(
+                    "{}{}{}",
+                    word_boundary(term.chars().next()),
+                    regex::escape(term),
+                    word_boundary(term.chars().last())
+                )
+            })
+            .collect();
+        if alternatives.is_empty() {
+            return Ok(None);
+        }
+        let terms_re = Regex::new(&alternatives.join("|"))
+            .with_context(|| "Failed to build a pattern matching the glossary's terms")?;
+        Ok(Some(DictionaryTerms {
+            terms_re,
+            translations,
+        }))
+    }
+
+    /// Wrap the terms in the text of an HTML segment in `<mstrans:dictionary>` tags giving their translations, returning `None` if it contains none.
+    fn mark_up(&self, html: &str) -> Option<String> {
+        static TAG_RE: OnceLock<Regex> = OnceLock::new();
+        let tag_re = TAG_RE.get_or_init(|| Regex::new(r"<[^>]*>").unwrap());
+        let mark_up_text = |text: &str| -> String {
+            self.terms_re
+                .replace_all(text, |captures: &regex::Captures| {
+                    let term = &captures[0];
+                    format!(
+                        "<mstrans:dictionary translation=\"{}\">{}</mstrans:dictionary>",
+                        escape_html(&self.translations[term]).replace('"', "&quot;"),
+                        term
+                    )
+                })
+                .into_owned()
+        };
+
+        // Leave the tags alone, in case a term appears in them
+        let mut marked_up = String::new();
+        let mut text_start = 0;
+        for tag in tag_re.find_iter(html) {
+            marked_up.push_str(&mark_up_text(&html[text_start..tag.start()]));
+            marked_up.push_str(tag.as_str());
+            text_start = tag.end();
+        }
+        marked_up.push_str(&mark_up_text(&html[text_start..]));
+        (marked_up != html).then_some(marked_up)
+    }
+}
+
+/// Remove any dynamic dictionary tags which were left in a translation, keeping their contents.
+fn strip_dictionary_markup(text: &str) -> String {
+    static DICTIONARY_TAG_RE: OnceLock<Regex> = OnceLock::new();
+    let dictionary_tag_re =
+        DICTIONARY_TAG_RE.get_or_init(|| Regex::new(r"(?i)</?mstrans:dictionary\b[^>]*>").unwrap());
+    dictionary_tag_re.replace_all(text, "").into_owned()
+}
+
+/// Client for the Azure Text Translation API.
+pub struct TranslationClient {
+    endpoint: String,
+    version: String,
+    authenticator: Authenticator,
+    region: String,
+    resource_id: Option<String>,
+    send_region: bool,
+    category: Option<String>,
+    glossary: Option<Glossary>,
+    max_concurrent_requests: usize,
+    retry_policy: RetryPolicy,
+}
+
+impl TranslationClient {
+    /// A client for the resource in this region, authenticating in this way, e.g. with its key or with Microsoft Entra ID.
+    pub fn new(auth: AzureAuth, region: String) -> TranslationClient {
+        TranslationClient {
+            endpoint: AzureCloud::default().endpoint().to_string(),
+            version: DEFAULT_VERSION.to_string(),
+            authenticator: Authenticator::new(auth),
+            region,
+            resource_id: None,
+            category: None,
+            glossary: None,
+            send_region: true,
+            max_concurrent_requests: 1,
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+
+    /// Send requests to this endpoint rather than the global one, e.g. a custom domain such as `https://<name>.cognitiveservices.azure.com/translator/text/v3.0`, or a private endpoint.
+    pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = endpoint.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Send requests to the global endpoint of this cloud.
+    pub fn with_cloud(self, cloud: AzureCloud) -> Self {
+        self.with_endpoint(cloud.endpoint())
+    }
+
+    /// Use this version of the API rather than 3.0.
+    pub fn with_api_version(mut self, version: &str) -> Self {
+        self.version = version.to_string();
+        self
+    }
+
+    /// Use the resource in this region.
+    pub fn with_region(mut self, region: &str) -> Self {
+        self.region = region.to_string();
+        self
+    }
+
+    /// Whether to send the resource's region with each request, which single-service global resources and custom domains don't need.
+    pub fn with_region_header(mut self, send_region: bool) -> Self {
+        self.send_region = send_region;
+        self
+    }
+
+    /// Send the resource's ID (`/subscriptions/<id>/resourceGroups/<group>/providers/Microsoft.CognitiveServices/accounts/<name>`) with requests, which regional resources need when authenticating with Microsoft Entra ID.
+    pub fn with_resource_id(mut self, resource_id: &str) -> Self {
+        self.resource_id = Some(resource_id.to_string());
+        self
+    }
+
+    /// Translate with the Custom Translator model of this category, rather than the general model.
+    pub fn with_category(mut self, category: &str) -> Self {
+        self.category = Some(category.to_string());
+        self
+    }
+
+    /// Translate the glossary's terms as it says, by marking them up with their translations.
+    pub fn with_glossary(mut self, glossary: Glossary) -> Self {
+        self.glossary = Some(glossary);
+        self
+    }
+
+    /// Retry requests which are throttled or fail with transient errors according to this policy.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Send up to this many /translate requests at once, when the sentences don't fit in a single request.
+    pub fn with_max_concurrent_requests(mut self, max_concurrent_requests: usize) -> Self {
+        self.max_concurrent_requests = max_concurrent_requests.max(1);
+        self
+    }
+
+    fn url(&self, path: &str, params: &[(&str, String)]) -> Result<reqwest::Url> {
+        reqwest::Url::parse_with_params(&format!("{}{}", self.endpoint, path), params)
+            .with_context(|| "Failed to generate request URL with params")
+    }
+
+    /// POST the texts to the API, returning the response items.
+    async fn post<T: serde::de::DeserializeOwned>(
+        &self,
+        client: &reqwest::Client,
+        url: &reqwest::Url,
+        texts: &[String],
+    ) -> Result<Vec<T>> {
+        let req_body: Vec<TranslateRequestItem> = texts
+            .iter()
+            .map(|s| TranslateRequestItem { text: s.clone() })
+            .collect();
+
+        // Retries of the request share a trace ID, so they can be correlated in Azure's logs
+        let trace_id = Uuid::new_v4().to_string();
+        let (req_body, trace_id) = (&req_body, trace_id.as_str());
+        // The access token is fetched for each attempt, as it may expire while backing off
+        let send = || {
+            retry::send_with(
+                &self.retry_policy,
+                "Azure translation API",
+                move || async move {
+                    let (auth_header, auth_value) = self
+                        .authenticator
+                        .header(client, &self.retry_policy)
+                        .await?;
+                    let mut request = client
+                        .post(url.clone())
+                        .json(req_body)
+                        .header(auth_header, auth_value)
+                        .header("X-ClientTraceId", trace_id);
+                    if self.send_region {
+                        request =
+                            request.header("Ocp-Apim-Subscription-Region", self.region.clone());
+                    }
+                    if let Some(resource_id) = &self.resource_id {
+                        request = request.header("Ocp-Apim-ResourceId", resource_id.clone());
+                    }
+                    Ok(request)
+                },
+            )
+        };
+        let resp = match send().await {
+            // A token which was revoked, or expired early, is replaced once
+            Err(e)
+                if self.authenticator.can_refresh()
+                    && e.downcast_ref::<retry::ResponseError>()
+                        .is_some_and(|e| e.status == reqwest::StatusCode::UNAUTHORIZED) =>
+            {
+                self.authenticator.invalidate().await;
+                send().await?
+            }
+            resp => resp?,
+        };
+
+        resp.json::<Vec<T>>()
+            .await
+            .with_context(|| "Failed to parse the response from the Azure translation API")
+    }
+
+    /// The segments with the glossary's terms marked up with their translations into the language, or `None` for those which contain no terms.
+    fn mark_up_glossary_terms(
+        &self,
+        segments: &[String],
+        language: Option<&Language>,
+    ) -> Result<Vec<Option<String>>> {
+        let dictionary_terms = match (&self.glossary, language) {
+            (Some(glossary), Some(language)) => DictionaryTerms::new(glossary.terms(language))
+                .with_context(|| format!("Failed to use the glossary's terms for {}", language))?,
+            _ => None,
+        };
+        Ok(segments
+            .iter()
+            .map(|s| dictionary_terms.as_ref().and_then(|d| d.mark_up(s)))
+            .collect())
+    }
+
+    /// Translate the segments, with the glossary's terms marked up with their translations into the target languages (which must share the same translations).
+    async fn translate_marked_up(
+        &self,
+        segments: &[String],
+        from: Option<&Language>,
+        to: &[Language],
+    ) -> Result<(Language, Vec<Vec<Translation>>)> {
+        let marked_up = self.mark_up_glossary_terms(segments, to.first())?;
+        let request_segments: Vec<String> = segments
+            .iter()
+            .zip(marked_up.iter())
+            .map(|(s, m)| m.clone().unwrap_or_else(|| s.clone()))
+            .collect();
+
+        let mut params = vec![
+            ("api-version", self.version.clone()),
+            // Sentences contain cue markup as HTML tags, which the API leaves untranslated
+            ("textType", "html".to_string()),
+            ("includeAlignment", "true".to_string()),
+        ];
+        params.extend(to.iter().map(|language| ("to", language.to_string())));
+        if let Some(source_language) = from {
+            params.push(("from", source_language.to_string()));
+        }
+        if let Some(category) = &self.category {
+            params.push(("category", category.clone()));
+        }
+        let url = self.url(TRANSLATE_PATH, &params)?;
+
+        // Azure counts the characters of each sentence once for every target language
+        let client = reqwest::Client::new();
+        let response_items = in_batches(
+            &request_segments,
+            REQUEST_LIMITS,
+            to.len(),
+            self.max_concurrent_requests,
+            |_, batch| self.post(&client, &url, batch),
+        )
+        .await?;
+
+        let (source_language, mut translations) = collect_translations(response_items, from, to)?;
+        for language_translations in translations.iter_mut() {
+            for (translation, marked_up) in language_translations.iter_mut().zip(marked_up.iter()) {
+                translation.text = strip_dictionary_markup(&translation.text);
+                if marked_up.is_some() {
+                    // The alignment is with the marked up sentence, so doesn't match the original
+                    translation.alignment = None;
+                }
+            }
+        }
+        Ok((source_language, translations))
+    }
+}
+
+#[async_trait]
+impl Translator for TranslationClient {
+    fn name(&self) -> &str {
+        "Azure"
+    }
+
+    fn cache_key(&self) -> String {
+        // Sovereign clouds may not support all the languages of the public one
+        if self.endpoint == AzureCloud::Public.endpoint() {
+            self.name().to_string()
+        } else {
+            format!("azure-{}", self.endpoint)
+        }
+    }
+
+    fn options_key(&self) -> String {
+        if self.category.is_none() && self.glossary.is_none() {
+            return String::new();
+        }
+        // The glossary may be large, so is identified by its hash
+        serde_json::json!({
+            "category": self.category,
+            "glossary": self.glossary.as_ref().map(Glossary::fingerprint),
+        })
+        .to_string()
+    }
+
+    fn billed_chars(&self, segments: &[String], to: &Language) -> Result<usize> {
+        // The glossary's markup is billed too
+        let marked_up = self.mark_up_glossary_terms(segments, Some(to))?;
+        Ok(segments
+            .iter()
+            .zip(marked_up)
+            .map(|(s, m)| m.map_or_else(|| s.chars().count(), |m| m.chars().count()))
+            .sum())
+    }
+
+    fn honours_notranslate(&self) -> bool {
+        true
+    }
+
+    async fn languages(&self) -> Result<BTreeMap<String, LanguageInfo>> {
+        let url = self.url(
+            LANGUAGES_PATH,
+            &[
+                ("api-version", self.version.clone()),
+                ("scope", "translation".to_string()),
+            ],
+        )?;
+
+        let client = reqwest::Client::new();
+        let resp = retry::send(
+            &self.retry_policy,
+            "Azure translation API /languages endpoint",
+            || client.get(url.clone()),
+        )
+        .await?;
+
+        let resp_body = resp
+            .json::<LanguagesResponse>()
+            .await
+            .with_context(|| "Failed to parse the response from the /languages endpoint")?;
+
+        Ok(resp_body.translation)
+    }
+
+    async fn detect_language(&self, segments: &[String]) -> Result<Language> {
+        let url = self.url(DETECT_PATH, &[("api-version", self.version.clone())])?;
+        let client = reqwest::Client::new();
+        let detected: Vec<TranslateResponseDetectedLanguage> = in_batches(
+            segments,
+            REQUEST_LIMITS,
+            1,
+            self.max_concurrent_requests,
+            |_, batch| self.post(&client, &url, batch),
+        )
+        .await?;
+        most_likely_language(detected.into_iter().map(|d| (d.language, d.score)))
+            .with_context(|| "No language detected")
+    }
+
+    async fn translate(
+        &self,
+        segments: &[String],
+        from: Option<&Language>,
+        to: &[Language],
+    ) -> Result<(Language, Vec<Vec<Translation>>)> {
+        match &self.glossary {
+            // Each language's translations of the terms need marking up in its own request
+            Some(glossary) if glossary.is_language_specific() && to.len() > 1 => {
+                let mut source_language = from.cloned();
+                let mut translations = vec![];
+                for language in to {
+                    let (language_source, mut language_translations) = self
+                        .translate_marked_up(segments, from, std::slice::from_ref(language))
+                        .await?;
+                    source_language.get_or_insert(language_source);
+                    translations.append(&mut language_translations);
+                }
+                Ok((
+                    source_language.context("No target languages")?,
+                    translations,
+                ))
+            }
+            _ => self.translate_marked_up(segments, from, to).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use wiremock::matchers::{body_string_contains, header, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn client(server: &MockServer) -> TranslationClient {
+        TranslationClient::new(AzureAuth::Key("key".to_string()), "region".to_string())
+            .with_endpoint(&server.uri())
+    }
+
+    #[test]
+    fn test_parse_alignment() {
+        assert_eq!(
+            parse_alignment("0:4-0:6 6:10-8:12"),
+            Some(vec![
+                AlignmentSpan {
+                    source: 0..5,
+                    target: 0..7
+                },
+                AlignmentSpan {
+                    source: 6..11,
+                    target: 8..13
+                },
+            ])
+        );
+        assert_eq!(parse_alignment(""), Some(vec![]));
+        assert_eq!(parse_alignment("0:4"), None);
+        assert_eq!(parse_alignment("4:0-0:1"), None);
+    }
+
+    #[test]
+    fn test_collect_translations() {
+        let response: Vec<TranslateResponseItem> = serde_json::from_str(
+            r#"[
+                {"detectedLanguage": {"language": "en", "score": 0.9}, "translations": [
+                    {"text": "Bonjour", "to": "fr"},
+                    {"text": "سلام", "to": "fa", "alignment": {"proj": "0:4-0:3"}}
+                ]},
+                {"detectedLanguage": {"language": "de", "score": 0.4}, "translations": [
+                    {"text": "Monde", "to": "fr"},
+                    {"text": "دنیا", "to": "fa"}
+                ]}
+            ]"#,
+        )
+        .unwrap();
+        let to = ["fa".parse().unwrap(), "fr".parse().unwrap()];
+        let (source_language, translations) = collect_translations(response, None, &to).unwrap();
+        assert_eq!(source_language.to_string(), "en");
+        assert_eq!(
+            translations
+                .iter()
+                .map(|t| t.iter().map(|t| t.text.as_str()).collect::<Vec<_>>())
+                .collect::<Vec<_>>(),
+            vec![vec!["سلام", "دنیا"], vec!["Bonjour", "Monde"]]
+        );
+        assert!(translations[0][0].alignment.is_some());
+        assert!(translations[1][0].alignment.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_translate_retries_when_throttled() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/translate"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/translate"))
+            .and(query_param("to", "fa"))
+            .and(header("Ocp-Apim-Subscription-Key", "key"))
+            .and(header("Ocp-Apim-Subscription-Region", "region"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+                {"detectedLanguage": {"language": "en", "score": 1.0}, "translations": [
+                    {"text": "سلام", "to": "fa", "alignment": {"proj": "0:4-0:3"}}
+                ]}
+            ])))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let (source_language, translations) = client(&server)
+            .translate(&["Hello".to_string()], None, &["fa".parse().unwrap()])
+            .await
+            .unwrap();
+        assert_eq!(source_language.to_string(), "en");
+        assert_eq!(translations[0][0].text, "سلام");
+    }
+
+    #[tokio::test]
+    async fn test_translate_reports_azure_error() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/translate"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(json!(
+                {"error": {"code": 400036, "message": "The target language is not valid."}}
+            )))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let error = client(&server)
+            .translate(&["Hello".to_string()], None, &["fa".parse().unwrap()])
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", error)
+            .contains("400 Bad Request (error 400036: The target language is not valid.)"));
+    }
+
+    #[tokio::test]
+    async fn test_detect_language() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/detect"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+                {"language": "fr", "score": 0.6},
+                {"language": "en", "score": 0.3},
+                {"language": "en", "score": 0.4}
+            ])))
+            .mount(&server)
+            .await;
+
+        let segments = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let language = client(&server).detect_language(&segments).await.unwrap();
+        assert_eq!(language.to_string(), "en");
+    }
+
+    #[tokio::test]
+    async fn test_custom_endpoint_with_bearer_token() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/translator/text/v3.0/detect"))
+            .and(query_param("api-version", "3.1"))
+            .and(header("Authorization", "Bearer token"))
+            .and(header("Ocp-Apim-ResourceId", "resource"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!([{"language": "de", "score": 1.0}])),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client =
+            TranslationClient::new(AzureAuth::BearerToken("token".to_string()), String::new())
+                .with_endpoint(&format!("{}/translator/text/v3.0/", server.uri()))
+                .with_api_version("3.1")
+                .with_resource_id("resource")
+                .with_region_header(false);
+        let language = client
+            .detect_language(&["Hallo".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(language.to_string(), "de");
+        let requests = server.received_requests().await.unwrap();
+        assert!(!requests[0]
+            .headers
+            .contains_key("Ocp-Apim-Subscription-Region"));
+        assert!(!requests[0]
+            .headers
+            .contains_key("Ocp-Apim-Subscription-Key"));
+    }
+
+    #[tokio::test]
+    async fn test_rejected_token_is_refreshed() {
+        let server = MockServer::start().await;
+        for token in ["revoked", "fresh"] {
+            Mock::given(method("POST"))
+                .and(path("/tenant/oauth2/v2.0/token"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(
+                    json!({"token_type": "Bearer", "expires_in": 3600, "access_token": token}),
+                ))
+                .up_to_n_times(1)
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+        Mock::given(method("POST"))
+            .and(path("/detect"))
+            .and(header("Authorization", "Bearer revoked"))
+            .respond_with(ResponseTemplate::new(401))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/detect"))
+            .and(header("Authorization", "Bearer fresh"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!([{"language": "de", "score": 1.0}])),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = TranslationClient::new(
+            AzureAuth::ClientCredentials {
+                token_endpoint: format!("{}/tenant/oauth2/v2.0/token", server.uri()),
+                scope: AzureCloud::Public.scope().to_string(),
+                client_id: "id".to_string(),
+                client_secret: "secret".to_string(),
+            },
+            "region".to_string(),
+        )
+        .with_endpoint(&server.uri());
+        let language = client
+            .detect_language(&["Hallo".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(language.to_string(), "de");
+    }
+
+    #[test]
+    fn test_mark_up_dictionary_terms() {
+        let terms = DictionaryTerms::new(BTreeMap::from([
+            ("Widgetron", "Widgetron"),
+            ("sign in", "se \"connecter\""),
+            ("AT&T", "AT&T"),
+        ]))
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            terms.mark_up("Sign in to <span id=\"0\">Widgetron</span> at AT&amp;T, then sign in.").unwrap(),
+            "Sign in to <span id=\"0\"><mstrans:dictionary translation=\"Widgetron\">Widgetron</mstrans:dictionary></span> \
+             at <mstrans:dictionary translation=\"AT&amp;T\">AT&amp;T</mstrans:dictionary>, \
+             then <mstrans:dictionary translation=\"se &quot;connecter&quot;\">sign in</mstrans:dictionary>."
+        );
+        assert_eq!(terms.mark_up("Two Widgetrons"), None);
+        assert!(DictionaryTerms::new(BTreeMap::new()).unwrap().is_none());
+        assert_eq!(
+            strip_dictionary_markup(
+                "Se <mstrans:dictionary translation=\"x\">connecter</mstrans:dictionary>"
+            ),
+            "Se connecter"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_translate_with_glossary_and_category() {
+        let server = MockServer::start().await;
+        for (language, translation) in [("fr", "connexion"), ("de", "Anmeldung")] {
+            Mock::given(method("POST"))
+                .and(path("/translate"))
+                .and(query_param("to", language))
+                .and(query_param("category", "custom"))
+                .and(body_string_contains(format!(
+                    "<mstrans:dictionary translation=\\\"{}\\\">sign in</mstrans:dictionary>",
+                    translation
+                )))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+                    {"translations": [{"text": "Hi", "to": language, "alignment": {"proj": "0:1-0:1"}}]},
+                    {"translations": [{
+                        "text": format!("<mstrans:dictionary translation=\"{0}\">{0}</mstrans:dictionary>", translation),
+                        "to": language,
+                        "alignment": {"proj": "0:6-0:8"}
+                    }]}
+                ])))
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+
+        let glossary =
+            Glossary::parse("source,fr,de\nsign in,connexion,Anmeldung\n", b',').unwrap();
+        let client = client(&server)
+            .with_category("custom")
+            .with_glossary(glossary);
+        let segments = ["Hi".to_string(), "sign in".to_string()];
+        assert_eq!(
+            client
+                .billed_chars(&segments, &"de".parse().unwrap())
+                .unwrap(),
+            "Hi<mstrans:dictionary translation=\"Anmeldung\">sign in</mstrans:dictionary>".len()
+        );
+        let (_, translations) = client
+            .translate(
+                &segments,
+                Some(&"en".parse().unwrap()),
+                &["fr".parse().unwrap(), "de".parse().unwrap()],
+            )
+            .await
+            .unwrap();
+        assert_eq!(translations[1][1].text, "Anmeldung");
+        // Only the sentence which was marked up loses its alignment
+        assert!(translations[1][0].alignment.is_some());
+        assert_eq!(translations[1][1].alignment, None);
+    }
+}
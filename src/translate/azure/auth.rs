@@ -0,0 +1,264 @@
+use super::AzureCloud;
+use crate::retry::{self, RetryPolicy};
+use anyhow::{Context, Result};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// Tokens from the issueToken endpoint don't say when they expire, but are valid for 10 minutes.
+static ISSUED_TOKEN_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// Fetch a new access token when the current one is this close to expiring, so it doesn't expire during a request.
+static TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(2 * 60);
+
+/// How to authenticate requests to the Azure Text Translation API.
+#[derive(Debug, Clone)]
+pub enum AzureAuth {
+    /// The resource's key.
+    Key(String),
+    /// A Microsoft Entra ID access token which has already been issued, e.g. for a managed identity. It can't be refreshed.
+    BearerToken(String),
+    /// Microsoft Entra ID access tokens for a service principal, from the OAuth 2.0 client credentials flow.
+    ClientCredentials {
+        /// The tenant's token endpoint, e.g. `https://login.microsoftonline.com/<tenant>/oauth2/v2.0/token`.
+        token_endpoint: String,
+        /// The scope to request tokens for, which depends on the cloud, e.g. `https://cognitiveservices.azure.com/.default`.
+        scope: String,
+        client_id: String,
+        client_secret: String,
+    },
+    /// Access tokens exchanged for the resource's key at an issueToken endpoint, e.g. `https://<region>.api.cognitive.microsoft.com/sts/v1.0/issueToken`.
+    IssueToken { token_endpoint: String, key: String },
+}
+
+impl AzureAuth {
+    /// The Microsoft Entra ID token endpoint of a tenant in a cloud.
+    pub fn tenant_token_endpoint(cloud: AzureCloud, tenant_id: &str) -> String {
+        format!("{}/{}/oauth2/v2.0/token", cloud.authority_host(), tenant_id)
+    }
+
+    /// The issueToken endpoint for resources in a region of a cloud, or the cloud's global one.
+    pub fn issue_token_endpoint(cloud: AzureCloud, region: &str) -> String {
+        let domain = cloud.cognitive_services_domain();
+        if region.is_empty() || region.eq_ignore_ascii_case("global") {
+            format!("https://{}/sts/v1.0/issueToken", domain)
+        } else {
+            format!("https://{}.{}/sts/v1.0/issueToken", region, domain)
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+#[derive(Debug, Clone)]
+struct AccessToken {
+    token: String,
+    expires_at: Instant,
+}
+
+/// Adds authentication to requests, fetching access tokens as needed and refreshing them before they expire.
+pub(super) struct Authenticator {
+    auth: AzureAuth,
+    token: Mutex<Option<AccessToken>>,
+}
+
+impl Authenticator {
+    pub fn new(auth: AzureAuth) -> Authenticator {
+        Authenticator {
+            auth,
+            token: Mutex::new(None),
+        }
+    }
+
+    /// Whether access tokens are fetched, so can be fetched again if one is rejected.
+    pub fn can_refresh(&self) -> bool {
+        matches!(
+            self.auth,
+            AzureAuth::ClientCredentials { .. } | AzureAuth::IssueToken { .. }
+        )
+    }
+
+    /// Forget the current access token, e.g. because it was rejected, so a new one is fetched for the next request.
+    pub async fn invalidate(&self) {
+        *self.token.lock().await = None;
+    }
+
+    /// The header (name and value) to authenticate a request with.
+    pub async fn header(
+        &self,
+        client: &reqwest::Client,
+        retry_policy: &RetryPolicy,
+    ) -> Result<(&'static str, String)> {
+        let token = match &self.auth {
+            AzureAuth::Key(key) => return Ok(("Ocp-Apim-Subscription-Key", key.clone())),
+            AzureAuth::BearerToken(token) => token.clone(),
+            _ => {
+                // Hold the lock while fetching, so concurrent requests share the new token
+                let mut token = self.token.lock().await;
+                match &*token {
+                    Some(t) if t.expires_at > Instant::now() + TOKEN_REFRESH_MARGIN => {
+                        t.token.clone()
+                    }
+                    _ => {
+                        let new_token = self.fetch_token(client, retry_policy).await?;
+                        let value = new_token.token.clone();
+                        *token = Some(new_token);
+                        value
+                    }
+                }
+            }
+        };
+        Ok(("Authorization", format!("Bearer {}", token)))
+    }
+
+    async fn fetch_token(
+        &self,
+        client: &reqwest::Client,
+        retry_policy: &RetryPolicy,
+    ) -> Result<AccessToken> {
+        let fetched_at = Instant::now();
+        match &self.auth {
+            AzureAuth::ClientCredentials {
+                token_endpoint,
+                scope,
+                client_id,
+                client_secret,
+            } => {
+                let form = [
+                    ("grant_type", "client_credentials"),
+                    ("client_id", client_id),
+                    ("client_secret", client_secret),
+                    ("scope", scope),
+                ];
+                let resp = retry::send(retry_policy, "Microsoft Entra ID token endpoint", || {
+                    client.post(token_endpoint).form(&form)
+                })
+                .await?;
+                let resp_body = resp.json::<TokenResponse>().await.with_context(|| {
+                    "Failed to parse the response from the Microsoft Entra ID token endpoint"
+                })?;
+                Ok(AccessToken {
+                    token: resp_body.access_token,
+                    expires_at: fetched_at + Duration::from_secs(resp_body.expires_in),
+                })
+            }
+            AzureAuth::IssueToken {
+                token_endpoint,
+                key,
+            } => {
+                let resp = retry::send(retry_policy, "Azure issueToken endpoint", || {
+                    client
+                        .post(token_endpoint)
+                        .header("Ocp-Apim-Subscription-Key", key)
+                        .header(reqwest::header::CONTENT_LENGTH, 0)
+                })
+                .await?;
+                let token = resp.text().await.with_context(|| {
+                    "Failed to read the access token from the issueToken endpoint"
+                })?;
+                Ok(AccessToken {
+                    token: token.trim().to_string(),
+                    expires_at: fetched_at + ISSUED_TOKEN_LIFETIME,
+                })
+            }
+            AzureAuth::Key(_) | AzureAuth::BearerToken(_) => {
+                unreachable!("Keys and pre-issued tokens aren't fetched")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use wiremock::matchers::{body_string_contains, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn test_client_credentials_token_refresh() {
+        let server = MockServer::start().await;
+        // The first token is about to expire, so is replaced by the second
+        Mock::given(method("POST"))
+            .and(path("/tenant/oauth2/v2.0/token"))
+            .and(body_string_contains("grant_type=client_credentials"))
+            .and(body_string_contains("client_id=id"))
+            .and(body_string_contains(
+                "scope=https%3A%2F%2Fcognitiveservices.azure.us%2F.default",
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(
+                json!({"token_type": "Bearer", "expires_in": 60, "access_token": "first"}),
+            ))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/tenant/oauth2/v2.0/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(
+                json!({"token_type": "Bearer", "expires_in": 3600, "access_token": "second"}),
+            ))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let authenticator = Authenticator::new(AzureAuth::ClientCredentials {
+            token_endpoint: format!("{}/tenant/oauth2/v2.0/token", server.uri()),
+            scope: AzureCloud::UsGovernment.scope().to_string(),
+            client_id: "id".to_string(),
+            client_secret: "secret".to_string(),
+        });
+        let client = reqwest::Client::new();
+        let policy = RetryPolicy::default();
+        for expected in ["Bearer first", "Bearer second", "Bearer second"] {
+            assert_eq!(
+                authenticator.header(&client, &policy).await.unwrap(),
+                ("Authorization", expected.to_string())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_issue_token() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/sts/v1.0/issueToken"))
+            .and(header("Ocp-Apim-Subscription-Key", "key"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("token\n"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let authenticator = Authenticator::new(AzureAuth::IssueToken {
+            token_endpoint: format!("{}/sts/v1.0/issueToken", server.uri()),
+            key: "key".to_string(),
+        });
+        let client = reqwest::Client::new();
+        let policy = RetryPolicy::default();
+        for _ in 0..2 {
+            assert_eq!(
+                authenticator.header(&client, &policy).await.unwrap(),
+                ("Authorization", "Bearer token".to_string())
+            );
+        }
+        assert_eq!(
+            AzureAuth::issue_token_endpoint(AzureCloud::Public, "westeurope"),
+            "https://westeurope.api.cognitive.microsoft.com/sts/v1.0/issueToken"
+        );
+        assert_eq!(
+            AzureAuth::issue_token_endpoint(AzureCloud::China, "global"),
+            "https://api.cognitive.azure.cn/sts/v1.0/issueToken"
+        );
+    }
+
+    #[test]
+    fn test_tenant_token_endpoint() {
+        assert_eq!(
+            AzureAuth::tenant_token_endpoint(AzureCloud::UsGovernment, "tenant"),
+            "https://login.microsoftonline.us/tenant/oauth2/v2.0/token"
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use super::{Language, LanguageInfo, Translation, Translator};
+use crate::memory::TranslationMemory;
+use crate::protect;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// A translator which looks sentences up in a translation memory before sending them to the translation API, and adds the API's translations to the memory.
+///
+/// Translations are only looked up when the source language is given, as they're keyed by it. Translations which have lost any of the sentence's protected text aren't added, so aren't reused.
+pub struct CachingTranslator {
+    translator: Box<dyn Translator>,
+    memory: Arc<TranslationMemory>,
+}
+
+impl CachingTranslator {
+    pub fn new(
+        translator: Box<dyn Translator>,
+        memory: Arc<TranslationMemory>,
+    ) -> CachingTranslator {
+        CachingTranslator { translator, memory }
+    }
+}
+
+#[async_trait]
+impl Translator for CachingTranslator {
+    fn name(&self) -> &str {
+        self.translator.name()
+    }
+
+    fn cache_key(&self) -> String {
+        self.translator.cache_key()
+    }
+
+    fn options_key(&self) -> String {
+        self.translator.options_key()
+    }
+
+    fn billed_chars(&self, segments: &[String], to: &Language) -> Result<usize> {
+        self.translator.billed_chars(segments, to)
+    }
+
+    fn honours_notranslate(&self) -> bool {
+        self.translator.honours_notranslate()
+    }
+
+    async fn languages(&self) -> Result<BTreeMap<String, LanguageInfo>> {
+        self.translator.languages().await
+    }
+
+    async fn detect_language(&self, segments: &[String]) -> Result<Language> {
+        self.translator.detect_language(segments).await
+    }
+
+    async fn translate(
+        &self,
+        segments: &[String],
+        from: Option<&Language>,
+        to: &[Language],
+    ) -> Result<(Language, Vec<Vec<Translation>>)> {
+        let Some(from) = from else {
+            return self.translator.translate(segments, from, to).await;
+        };
+        let backend = self.cache_key();
+        let options = self.options_key();
+        let mut translations = to
+            .iter()
+            .map(|language| {
+                segments
+                    .iter()
+                    .map(|segment| {
+                        self.memory
+                            .lookup(&backend, &options, from, language, segment)
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Translate the sentences which weren't found, together for languages missing the same ones
+        let mut missing: BTreeMap<Vec<usize>, Vec<usize>> = BTreeMap::new();
+        for (language_num, language_translations) in translations.iter().enumerate() {
+            let segment_nums: Vec<usize> = (0..segments.len())
+                .filter(|&i| language_translations[i].is_none())
+                .collect();
+            if !segment_nums.is_empty() {
+                missing.entry(segment_nums).or_default().push(language_num);
+            }
+        }
+        for (segment_nums, language_nums) in missing {
+            let missing_segments: Vec<String> =
+                segment_nums.iter().map(|&i| segments[i].clone()).collect();
+            let languages: Vec<Language> = language_nums.iter().map(|&l| to[l].clone()).collect();
+            let (_, new_translations) = self
+                .translator
+                .translate(&missing_segments, Some(from), &languages)
+                .await?;
+            for (&language_num, language_translations) in language_nums.iter().zip(new_translations)
+            {
+                self.memory.insert(
+                    &backend,
+                    &options,
+                    from,
+                    &to[language_num],
+                    missing_segments
+                        .iter()
+                        .zip(language_translations.iter())
+                        .filter(|(segment, translation)| {
+                            protect::keeps_protected_text(segment, &translation.text)
+                        }),
+                )?;
+                for (&segment_num, translation) in segment_nums.iter().zip(language_translations) {
+                    translations[language_num][segment_num] = Some(translation);
+                }
+            }
+        }
+
+        let translations = translations
+            .into_iter()
+            .map(|language_translations| {
+                language_translations
+                    .into_iter()
+                    .collect::<Option<Vec<_>>>()
+                    .context("Translation API returned too few translations")
+            })
+            .collect::<Result<_>>()?;
+        Ok((from.clone(), translations))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::translate::PseudoTranslator;
+    use std::path::Path;
+
+    #[tokio::test]
+    async fn test_cached_translations() {
+        let memory = Arc::new(TranslationMemory::open(Path::new(":memory:")).unwrap());
+        let translator = CachingTranslator::new(Box::new(PseudoTranslator::new()), memory.clone());
+        let en: Language = "en".parse().unwrap();
+        let en_xa: Language = "en-XA".parse().unwrap();
+        let ar_xb: Language = "ar-XB".parse().unwrap();
+
+        let (_, first) = translator
+            .translate(
+                &["Hi.".to_string()],
+                Some(&en),
+                std::slice::from_ref(&en_xa),
+            )
+            .await
+            .unwrap();
+        assert_eq!(memory.stats(), (0, 1));
+
+        // Only the new sentence is translated into en-XA, but both are into ar-XB
+        let segments = ["Hi.".to_string(), "Bye.".to_string()];
+        let (_, translations) = translator
+            .translate(&segments, Some(&en), &[en_xa, ar_xb])
+            .await
+            .unwrap();
+        assert_eq!(memory.stats(), (1, 4));
+        assert_eq!(translations[0][0], first[0][0]);
+        assert_eq!(translations[0][1].text, "[Ɓýéé.]");
+        assert_eq!(translations[1].len(), 2);
+        assert_eq!(memory.entries().unwrap().len(), 4);
+    }
+}
@@ -0,0 +1,297 @@
+use super::{
+    conventional_case, in_batches, language_direction, most_likely_language, Language,
+    LanguageInfo, RequestLimits, Translation, Translator,
+};
+use crate::retry::{self, RetryPolicy};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+
+static PRO_ENDPOINT: &str = "https://api.deepl.com";
+static FREE_ENDPOINT: &str = "https://api-free.deepl.com";
+static TRANSLATE_PATH: &str = "/v2/translate";
+static LANGUAGES_PATH: &str = "/v2/languages";
+
+/// DeepL allows 50 texts, and 128 KiB, in a single request. 30,000 characters keeps well within the size limit, even for text which is mostly multi-byte in UTF-8.
+static REQUEST_LIMITS: RequestLimits = RequestLimits {
+    max_segments: 50,
+    max_chars: 30_000,
+};
+
+#[derive(Debug, Clone, serde::Serialize)]
+struct TranslateRequest<'a> {
+    text: &'a [String],
+    target_lang: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source_lang: Option<String>,
+    tag_handling: &'static str,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct TranslateResponseTranslation {
+    detected_source_language: String,
+    text: String,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct TranslateResponse {
+    translations: Vec<TranslateResponseTranslation>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct LanguagesResponseLanguage {
+    language: String,
+    name: String,
+}
+
+/// Client for the DeepL API.
+pub struct DeepLClient {
+    endpoint: String,
+    auth_key: String,
+    max_concurrent_requests: usize,
+    retry_policy: RetryPolicy,
+}
+
+impl DeepLClient {
+    /// Create a client, using the DeepL API Free endpoint for free accounts' keys (which end in `:fx`).
+    pub fn new(auth_key: String) -> DeepLClient {
+        let endpoint = if auth_key.ends_with(":fx") {
+            FREE_ENDPOINT
+        } else {
+            PRO_ENDPOINT
+        };
+        DeepLClient {
+            endpoint: endpoint.to_string(),
+            auth_key,
+            max_concurrent_requests: 1,
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+
+    /// Send requests to this endpoint rather than the default one.
+    pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = endpoint.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Retry requests which are throttled or fail with transient errors according to this policy.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Send up to this many requests at once, when the sentences don't fit in a single request.
+    pub fn with_max_concurrent_requests(mut self, max_concurrent_requests: usize) -> Self {
+        self.max_concurrent_requests = max_concurrent_requests.max(1);
+        self
+    }
+
+    fn authorization(&self) -> String {
+        format!("DeepL-Auth-Key {}", self.auth_key)
+    }
+
+    async fn fetch_languages(
+        &self,
+        client: &reqwest::Client,
+        language_type: &str,
+    ) -> Result<Vec<LanguagesResponseLanguage>> {
+        let url = format!("{}{}", self.endpoint, LANGUAGES_PATH);
+        let resp = retry::send(&self.retry_policy, "DeepL API", || {
+            client
+                .get(&url)
+                .query(&[("type", language_type)])
+                .header("Authorization", self.authorization())
+        })
+        .await?;
+        resp.json()
+            .await
+            .with_context(|| "Failed to parse the response from the DeepL /languages endpoint")
+    }
+
+    /// Translate a batch of texts into one language, returning the detected source language and translation of each.
+    async fn translate_batch(
+        &self,
+        client: &reqwest::Client,
+        texts: &[String],
+        from: Option<&Language>,
+        to: &Language,
+    ) -> Result<Vec<TranslateResponseTranslation>> {
+        // DeepL only accepts the primary language of a source language, e.g. "EN" rather than "EN-GB"
+        let req_body = TranslateRequest {
+            text: texts,
+            target_lang: to.to_string().to_uppercase(),
+            source_lang: from.map(|l| l.to_string().split('-').next().unwrap_or("").to_uppercase()),
+            tag_handling: "html",
+        };
+        let url = format!("{}{}", self.endpoint, TRANSLATE_PATH);
+        let resp = retry::send(&self.retry_policy, "DeepL API", || {
+            client
+                .post(&url)
+                .json(&req_body)
+                .header("Authorization", self.authorization())
+        })
+        .await?;
+        let resp_body = resp
+            .json::<TranslateResponse>()
+            .await
+            .with_context(|| "Failed to parse the response from the DeepL API")?;
+        Ok(resp_body.translations)
+    }
+}
+
+#[async_trait]
+impl Translator for DeepLClient {
+    fn name(&self) -> &str {
+        "DeepL"
+    }
+
+    async fn languages(&self) -> Result<BTreeMap<String, LanguageInfo>> {
+        // DeepL lists source and target languages separately, with regional variants only as targets
+        let client = reqwest::Client::new();
+        let mut languages = BTreeMap::new();
+        for language_type in ["source", "target"] {
+            for language in self.fetch_languages(&client, language_type).await? {
+                let code = conventional_case(&language.language);
+                languages.insert(
+                    code.clone(),
+                    LanguageInfo {
+                        native_name: language.name.clone(),
+                        name: language.name,
+                        direction: language_direction(&code),
+                    },
+                );
+            }
+        }
+        Ok(languages)
+    }
+
+    async fn detect_language(&self, segments: &[String]) -> Result<Language> {
+        // DeepL only detects languages as part of translating
+        let (language, _) = self.translate(segments, None, &["en-US".parse()?]).await?;
+        Ok(language)
+    }
+
+    async fn translate(
+        &self,
+        segments: &[String],
+        from: Option<&Language>,
+        to: &[Language],
+    ) -> Result<(Language, Vec<Vec<Translation>>)> {
+        let client = reqwest::Client::new();
+        let mut translations = vec![];
+        let mut detected_languages = vec![];
+        for language in to {
+            let language_translations = in_batches(
+                segments,
+                REQUEST_LIMITS,
+                1,
+                self.max_concurrent_requests,
+                |_, batch| self.translate_batch(&client, batch, from, language),
+            )
+            .await?;
+            for translation in language_translations.iter() {
+                detected_languages.push((
+                    conventional_case(&translation.detected_source_language).parse()?,
+                    1.0,
+                ));
+            }
+            translations.push(
+                language_translations
+                    .into_iter()
+                    .map(|t| Translation {
+                        text: t.text,
+                        alignment: None,
+                    })
+                    .collect(),
+            );
+        }
+
+        let source_language = match from {
+            Some(source_language) => source_language.clone(),
+            None => most_likely_language(detected_languages).map_or_else(|| "en".parse(), Ok)?,
+        };
+        Ok((source_language, translations))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use wiremock::matchers::{body_partial_json, header, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn client(server: &MockServer) -> DeepLClient {
+        DeepLClient::new("key:fx".to_string()).with_endpoint(&server.uri())
+    }
+
+    #[tokio::test]
+    async fn test_translate() {
+        let server = MockServer::start().await;
+        for (target, text) in [
+            ("DE", "Hallo <span id=\"0\">Welt</span>"),
+            ("PT-BR", "Olá <span id=\"0\">mundo</span>"),
+        ] {
+            Mock::given(method("POST"))
+                .and(path("/v2/translate"))
+                .and(header("Authorization", "DeepL-Auth-Key key:fx"))
+                .and(body_partial_json(
+                    json!({"target_lang": target, "tag_handling": "html"}),
+                ))
+                .respond_with(
+                    ResponseTemplate::new(200).set_body_json(json!({"translations": [
+                        {"detected_source_language": "EN", "text": text}
+                    ]})),
+                )
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+
+        let (source_language, translations) = client(&server)
+            .translate(
+                &["Hello <span id=\"0\">world</span>".to_string()],
+                None,
+                &["de".parse().unwrap(), "pt-BR".parse().unwrap()],
+            )
+            .await
+            .unwrap();
+        assert_eq!(source_language.to_string(), "en");
+        assert_eq!(translations[0][0].text, "Hallo <span id=\"0\">Welt</span>");
+        assert_eq!(translations[1][0].text, "Olá <span id=\"0\">mundo</span>");
+    }
+
+    #[tokio::test]
+    async fn test_languages() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/v2/languages"))
+            .and(query_param("type", "source"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+                {"language": "AR", "name": "Arabic"},
+                {"language": "EN", "name": "English"}
+            ])))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/v2/languages"))
+            .and(query_param("type", "target"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+                {"language": "AR", "name": "Arabic"},
+                {"language": "EN-GB", "name": "English (British)"},
+                {"language": "ZH-HANS", "name": "Chinese (simplified)"}
+            ])))
+            .mount(&server)
+            .await;
+
+        let languages = client(&server).languages().await.unwrap();
+        assert_eq!(
+            languages.keys().collect::<Vec<_>>(),
+            vec!["ar", "en", "en-GB", "zh-Hans"]
+        );
+        assert!(matches!(
+            languages["ar"].direction,
+            super::super::Direction::Rtl
+        ));
+    }
+}
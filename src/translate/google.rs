@@ -0,0 +1,312 @@
+use super::{
+    in_batches, language_direction, most_likely_language, Language, LanguageInfo, RequestLimits,
+    Translation, Translator,
+};
+use crate::retry::{self, RetryPolicy};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+
+static DEFAULT_ENDPOINT: &str = "https://translation.googleapis.com";
+static TRANSLATE_PATH: &str = "/language/translate/v2";
+static DETECT_PATH: &str = "/language/translate/v2/detect";
+static LANGUAGES_PATH: &str = "/language/translate/v2/languages";
+
+/// Google allows 128 texts in a single request, and recommends keeping requests to 30,000 characters.
+static REQUEST_LIMITS: RequestLimits = RequestLimits {
+    max_segments: 128,
+    max_chars: 30_000,
+};
+
+#[derive(Debug, Clone, serde::Serialize)]
+struct TranslateRequest<'a> {
+    q: &'a [String],
+    target: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
+    format: &'static str,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+struct DetectRequest<'a> {
+    q: &'a [String],
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct Response<T> {
+    data: T,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct TranslateResponseTranslation {
+    #[serde(rename = "translatedText")]
+    translated_text: String,
+    #[serde(rename = "detectedSourceLanguage")]
+    detected_source_language: Option<Language>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct TranslateResponse {
+    translations: Vec<TranslateResponseTranslation>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct DetectResponseDetection {
+    language: Language,
+    confidence: f32,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct DetectResponse {
+    detections: Vec<Vec<DetectResponseDetection>>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct LanguagesResponseLanguage {
+    language: String,
+    name: String,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct LanguagesResponse {
+    languages: Vec<LanguagesResponseLanguage>,
+}
+
+/// Client for the Google Cloud Translation API (Basic edition), authenticated with an API key.
+pub struct GoogleClient {
+    endpoint: String,
+    api_key: String,
+    max_concurrent_requests: usize,
+    retry_policy: RetryPolicy,
+}
+
+impl GoogleClient {
+    pub fn new(api_key: String) -> GoogleClient {
+        GoogleClient {
+            endpoint: DEFAULT_ENDPOINT.to_string(),
+            api_key,
+            max_concurrent_requests: 1,
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+
+    /// Send requests to this endpoint rather than the default one.
+    pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = endpoint.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Retry requests which are throttled or fail with transient errors according to this policy.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Send up to this many requests at once, when the sentences don't fit in a single request.
+    pub fn with_max_concurrent_requests(mut self, max_concurrent_requests: usize) -> Self {
+        self.max_concurrent_requests = max_concurrent_requests.max(1);
+        self
+    }
+
+    async fn post<B: serde::Serialize, T: serde::de::DeserializeOwned>(
+        &self,
+        client: &reqwest::Client,
+        path: &str,
+        body: &B,
+    ) -> Result<T> {
+        let url = format!("{}{}", self.endpoint, path);
+        let resp = retry::send(&self.retry_policy, "Google Cloud Translation API", || {
+            client
+                .post(&url)
+                .query(&[("key", &self.api_key)])
+                .json(body)
+        })
+        .await?;
+        let resp_body = resp.json::<Response<T>>().await.with_context(|| {
+            "Failed to parse the response from the Google Cloud Translation API"
+        })?;
+        Ok(resp_body.data)
+    }
+}
+
+#[async_trait]
+impl Translator for GoogleClient {
+    fn name(&self) -> &str {
+        "Google"
+    }
+
+    async fn languages(&self) -> Result<BTreeMap<String, LanguageInfo>> {
+        let client = reqwest::Client::new();
+        let url = format!("{}{}", self.endpoint, LANGUAGES_PATH);
+        let resp = retry::send(&self.retry_policy, "Google Cloud Translation API", || {
+            client
+                .get(&url)
+                .query(&[("key", self.api_key.as_str()), ("target", "en")])
+        })
+        .await?;
+        let resp_body = resp
+            .json::<Response<LanguagesResponse>>()
+            .await
+            .with_context(|| "Failed to parse the response from the Google languages endpoint")?;
+        Ok(resp_body
+            .data
+            .languages
+            .into_iter()
+            .map(|l| {
+                let info = LanguageInfo {
+                    native_name: l.name.clone(),
+                    name: l.name,
+                    direction: language_direction(&l.language),
+                };
+                (l.language, info)
+            })
+            .collect())
+    }
+
+    async fn detect_language(&self, segments: &[String]) -> Result<Language> {
+        let client = &reqwest::Client::new();
+        let detections: Vec<Vec<DetectResponseDetection>> = in_batches(
+            segments,
+            REQUEST_LIMITS,
+            1,
+            self.max_concurrent_requests,
+            |_, batch| async move {
+                let response: DetectResponse = self
+                    .post(client, DETECT_PATH, &DetectRequest { q: batch })
+                    .await?;
+                Ok(response.detections)
+            },
+        )
+        .await?;
+        most_likely_language(
+            detections
+                .into_iter()
+                .flatten()
+                .map(|d| (d.language, d.confidence)),
+        )
+        .with_context(|| "No language detected")
+    }
+
+    async fn translate(
+        &self,
+        segments: &[String],
+        from: Option<&Language>,
+        to: &[Language],
+    ) -> Result<(Language, Vec<Vec<Translation>>)> {
+        let client = reqwest::Client::new();
+        let mut translations = vec![];
+        let mut detected_languages = vec![];
+        for language in to {
+            let language_translations: Vec<TranslateResponseTranslation> = in_batches(
+                segments,
+                REQUEST_LIMITS,
+                1,
+                self.max_concurrent_requests,
+                |_, batch| {
+                    let client = &client;
+                    async move {
+                        let req_body = TranslateRequest {
+                            q: batch,
+                            target: language.to_string(),
+                            source: from.map(|l| l.to_string()),
+                            // Sentences contain cue markup as HTML tags, which the API leaves untranslated
+                            format: "html",
+                        };
+                        let response: TranslateResponse =
+                            self.post(client, TRANSLATE_PATH, &req_body).await?;
+                        Ok(response.translations)
+                    }
+                },
+            )
+            .await?;
+            detected_languages.extend(
+                language_translations
+                    .iter()
+                    .filter_map(|t| t.detected_source_language.clone())
+                    .map(|l| (l, 1.0)),
+            );
+            translations.push(
+                language_translations
+                    .into_iter()
+                    .map(|t| Translation {
+                        text: t.translated_text,
+                        alignment: None,
+                    })
+                    .collect(),
+            );
+        }
+
+        let source_language = match from {
+            Some(source_language) => source_language.clone(),
+            None => most_likely_language(detected_languages).map_or_else(|| "en".parse(), Ok)?,
+        };
+        Ok((source_language, translations))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use wiremock::matchers::{body_partial_json, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn client(server: &MockServer) -> GoogleClient {
+        GoogleClient::new("key".to_string()).with_endpoint(&server.uri())
+    }
+
+    #[tokio::test]
+    async fn test_translate() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/language/translate/v2"))
+            .and(query_param("key", "key"))
+            .and(body_partial_json(json!({"target": "fr", "format": "html"})))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"data": {"translations": [
+                {"translatedText": "C&#39;est <span id=\"0\">ça</span>", "detectedSourceLanguage": "en"},
+                {"translatedText": "Oui", "detectedSourceLanguage": "en"}
+            ]}})))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let (source_language, translations) = client(&server)
+            .translate(
+                &[
+                    "That's <span id=\"0\">it</span>".to_string(),
+                    "Yes".to_string(),
+                ],
+                None,
+                &["fr".parse().unwrap()],
+            )
+            .await
+            .unwrap();
+        assert_eq!(source_language.to_string(), "en");
+        assert_eq!(
+            translations[0]
+                .iter()
+                .map(|t| t.text.as_str())
+                .collect::<Vec<_>>(),
+            vec!["C&#39;est <span id=\"0\">ça</span>", "Oui"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_detect_language() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/language/translate/v2/detect"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({"data": {"detections": [
+                    [{"language": "de", "confidence": 0.9, "isReliable": false}],
+                    [{"language": "nl", "confidence": 0.5, "isReliable": false}]
+                ]}})),
+            )
+            .mount(&server)
+            .await;
+
+        let segments = vec!["Hallo".to_string(), "Dag".to_string()];
+        let language = client(&server).detect_language(&segments).await.unwrap();
+        assert_eq!(language.to_string(), "de");
+    }
+}
@@ -0,0 +1,317 @@
+use super::{
+    in_batches, language_direction, most_likely_language, Language, LanguageInfo, RequestLimits,
+    Translation, Translator,
+};
+use crate::retry::{self, RetryPolicy};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+
+static TRANSLATE_PATH: &str = "/translate";
+static DETECT_PATH: &str = "/detect";
+static LANGUAGES_PATH: &str = "/languages";
+
+/// LibreTranslate servers set their own limits, so keep requests modest.
+static REQUEST_LIMITS: RequestLimits = RequestLimits {
+    max_segments: 50,
+    max_chars: 10_000,
+};
+
+#[derive(Debug, Clone, serde::Serialize)]
+struct TranslateRequest<'a> {
+    q: &'a [String],
+    source: String,
+    target: String,
+    format: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api_key: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+struct DetectRequest<'a> {
+    q: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api_key: Option<&'a str>,
+}
+
+/// A field which holds a single value or, when translating several texts at once, a list of values.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T> OneOrMany<T> {
+    fn into_vec(self) -> Vec<T> {
+        match self {
+            OneOrMany::One(value) => vec![value],
+            OneOrMany::Many(values) => values,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct DetectedLanguage {
+    language: Language,
+    /// Confidence as a percentage.
+    confidence: f32,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct TranslateResponse {
+    #[serde(rename = "translatedText")]
+    translated_text: OneOrMany<String>,
+    #[serde(rename = "detectedLanguage")]
+    detected_language: Option<OneOrMany<DetectedLanguage>>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct LanguagesResponseLanguage {
+    code: String,
+    name: String,
+}
+
+/// Client for a LibreTranslate server, or another server with a compatible API.
+pub struct LibreTranslateClient {
+    endpoint: String,
+    api_key: Option<String>,
+    max_concurrent_requests: usize,
+    retry_policy: RetryPolicy,
+}
+
+impl LibreTranslateClient {
+    /// Create a client for the server at `endpoint`, e.g. `http://localhost:5000`, which may require an API key.
+    pub fn new(endpoint: &str, api_key: Option<String>) -> LibreTranslateClient {
+        LibreTranslateClient {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            api_key,
+            max_concurrent_requests: 1,
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+
+    /// Retry requests which are throttled or fail with transient errors according to this policy.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Send up to this many requests at once, when the sentences don't fit in a single request.
+    pub fn with_max_concurrent_requests(mut self, max_concurrent_requests: usize) -> Self {
+        self.max_concurrent_requests = max_concurrent_requests.max(1);
+        self
+    }
+
+    async fn post<B: serde::Serialize, T: serde::de::DeserializeOwned>(
+        &self,
+        client: &reqwest::Client,
+        path: &str,
+        body: &B,
+    ) -> Result<T> {
+        let url = format!("{}{}", self.endpoint, path);
+        let resp = retry::send(&self.retry_policy, "LibreTranslate API", || {
+            client.post(&url).json(body)
+        })
+        .await?;
+        resp.json::<T>()
+            .await
+            .with_context(|| "Failed to parse the response from the LibreTranslate API")
+    }
+}
+
+#[async_trait]
+impl Translator for LibreTranslateClient {
+    fn name(&self) -> &str {
+        "LibreTranslate"
+    }
+
+    fn cache_key(&self) -> String {
+        format!("libretranslate-{}", self.endpoint)
+    }
+
+    async fn languages(&self) -> Result<BTreeMap<String, LanguageInfo>> {
+        let client = reqwest::Client::new();
+        let url = format!("{}{}", self.endpoint, LANGUAGES_PATH);
+        let resp = retry::send(&self.retry_policy, "LibreTranslate API", || {
+            client.get(&url)
+        })
+        .await?;
+        let resp_body = resp
+            .json::<Vec<LanguagesResponseLanguage>>()
+            .await
+            .with_context(|| {
+                "Failed to parse the response from the LibreTranslate /languages endpoint"
+            })?;
+        Ok(resp_body
+            .into_iter()
+            .map(|l| {
+                let info = LanguageInfo {
+                    native_name: l.name.clone(),
+                    name: l.name,
+                    direction: language_direction(&l.code),
+                };
+                (l.code, info)
+            })
+            .collect())
+    }
+
+    async fn detect_language(&self, segments: &[String]) -> Result<Language> {
+        // The /detect endpoint takes a single text
+        let client = reqwest::Client::new();
+        let req_body = DetectRequest {
+            q: segments.join("\n"),
+            api_key: self.api_key.as_deref(),
+        };
+        let detected: Vec<DetectedLanguage> = self.post(&client, DETECT_PATH, &req_body).await?;
+        detected
+            .into_iter()
+            .max_by(|a, b| a.confidence.total_cmp(&b.confidence))
+            .map(|d| d.language)
+            .with_context(|| "No language detected")
+    }
+
+    async fn translate(
+        &self,
+        segments: &[String],
+        from: Option<&Language>,
+        to: &[Language],
+    ) -> Result<(Language, Vec<Vec<Translation>>)> {
+        let client = &reqwest::Client::new();
+        let mut translations = vec![];
+        let mut detected_languages = vec![];
+        for language in to {
+            let language_translations: Vec<(String, Option<DetectedLanguage>)> = in_batches(
+                segments,
+                REQUEST_LIMITS,
+                1,
+                self.max_concurrent_requests,
+                |_, batch| async move {
+                    let req_body = TranslateRequest {
+                        q: batch,
+                        source: from.map_or("auto".to_string(), |l| l.to_string()),
+                        target: language.to_string(),
+                        // Sentences contain cue markup as HTML tags, which the API leaves untranslated
+                        format: "html",
+                        api_key: self.api_key.as_deref(),
+                    };
+                    let response: TranslateResponse =
+                        self.post(client, TRANSLATE_PATH, &req_body).await?;
+                    let texts = response.translated_text.into_vec();
+                    let mut detected = response
+                        .detected_language
+                        .map(OneOrMany::into_vec)
+                        .unwrap_or_default()
+                        .into_iter();
+                    Ok(texts.into_iter().map(|t| (t, detected.next())).collect())
+                },
+            )
+            .await?;
+            let (texts, detected): (Vec<_>, Vec<_>) = language_translations.into_iter().unzip();
+            detected_languages.extend(
+                detected
+                    .into_iter()
+                    .flatten()
+                    .map(|d| (d.language, d.confidence)),
+            );
+            translations.push(
+                texts
+                    .into_iter()
+                    .map(|text| Translation {
+                        text,
+                        alignment: None,
+                    })
+                    .collect(),
+            );
+        }
+
+        let source_language = match from {
+            Some(source_language) => source_language.clone(),
+            None => most_likely_language(detected_languages).map_or_else(|| "en".parse(), Ok)?,
+        };
+        Ok((source_language, translations))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use wiremock::matchers::{body_partial_json, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[tokio::test]
+    async fn test_translate() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/translate"))
+            .and(body_partial_json(json!({
+                "source": "auto", "target": "es", "format": "html", "api_key": "secret"
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "translatedText": ["Hola <span id=\"0\">mundo</span>", "Adiós"],
+                "detectedLanguage": [
+                    {"confidence": 90.0, "language": "en"},
+                    {"confidence": 40.0, "language": "fr"}
+                ]
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = LibreTranslateClient::new(&server.uri(), Some("secret".to_string()));
+        let (source_language, translations) = client
+            .translate(
+                &[
+                    "Hello <span id=\"0\">world</span>".to_string(),
+                    "Goodbye".to_string(),
+                ],
+                None,
+                &["es".parse().unwrap()],
+            )
+            .await
+            .unwrap();
+        assert_eq!(source_language.to_string(), "en");
+        assert_eq!(
+            translations[0]
+                .iter()
+                .map(|t| t.text.as_str())
+                .collect::<Vec<_>>(),
+            vec!["Hola <span id=\"0\">mundo</span>", "Adiós"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_languages_and_detect() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/languages"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+                {"code": "en", "name": "English", "targets": ["ar", "en"]},
+                {"code": "ar", "name": "Arabic", "targets": ["ar", "en"]}
+            ])))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/detect"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+                {"confidence": 30.0, "language": "en"},
+                {"confidence": 85.0, "language": "ar"}
+            ])))
+            .mount(&server)
+            .await;
+
+        let client = LibreTranslateClient::new(&format!("{}/", server.uri()), None);
+        let languages = client.languages().await.unwrap();
+        assert_eq!(languages.keys().collect::<Vec<_>>(), vec!["ar", "en"]);
+        assert!(matches!(
+            languages["ar"].direction,
+            super::super::Direction::Rtl
+        ));
+        let language = client
+            .detect_language(&["مرحبا".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(language.to_string(), "ar");
+    }
+}
@@ -0,0 +1,152 @@
+use super::{Language, LanguageInfo, Translation, Translator};
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// The characters sent to a translation API.
+#[derive(Debug, Clone, Default)]
+pub struct Usage {
+    /// Characters sent to detect the source language.
+    pub detection_chars: usize,
+    /// Characters sent to translate into each target language.
+    pub translation_chars: BTreeMap<Language, usize>,
+}
+
+impl Usage {
+    pub fn total(&self) -> usize {
+        self.detection_chars + self.translation_chars.values().sum::<usize>()
+    }
+}
+
+/// The number of characters translation APIs bill for translating the segments into one language: Unicode code points, including markup.
+pub fn billed_chars(segments: &[String]) -> usize {
+    segments.iter().map(|s| s.chars().count()).sum()
+}
+
+/// A translator which counts the characters sent to the translation API, and refuses to send any more once they would exceed a budget.
+///
+/// Characters are counted as the translation API bills them (see `Translator::billed_chars`) when they're sent, whether or not the request succeeds, once for each target language.
+pub struct MeteredTranslator {
+    translator: Box<dyn Translator>,
+    usage: Arc<Mutex<Usage>>,
+    max_chars: Option<usize>,
+}
+
+impl MeteredTranslator {
+    pub fn new(translator: Box<dyn Translator>, usage: Arc<Mutex<Usage>>) -> MeteredTranslator {
+        MeteredTranslator {
+            translator,
+            usage,
+            max_chars: None,
+        }
+    }
+
+    /// Don't send more than this many characters in total.
+    pub fn with_max_chars(mut self, max_chars: usize) -> Self {
+        self.max_chars = Some(max_chars);
+        self
+    }
+
+    /// Count characters which are about to be sent, unless they would take the total over the budget.
+    fn meter(&self, chars: usize, count: impl FnOnce(&mut Usage)) -> Result<()> {
+        let mut usage = self.usage.lock().unwrap();
+        if let Some(max_chars) = self.max_chars {
+            if usage.total() + chars > max_chars {
+                bail!(
+                    "Sending another {} characters to the translation API would exceed the budget of {} characters ({} already sent)",
+                    chars,
+                    max_chars,
+                    usage.total()
+                );
+            }
+        }
+        count(&mut usage);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Translator for MeteredTranslator {
+    fn name(&self) -> &str {
+        self.translator.name()
+    }
+
+    fn cache_key(&self) -> String {
+        self.translator.cache_key()
+    }
+
+    fn options_key(&self) -> String {
+        self.translator.options_key()
+    }
+
+    fn billed_chars(&self, segments: &[String], to: &Language) -> Result<usize> {
+        self.translator.billed_chars(segments, to)
+    }
+
+    fn honours_notranslate(&self) -> bool {
+        self.translator.honours_notranslate()
+    }
+
+    async fn languages(&self) -> Result<BTreeMap<String, LanguageInfo>> {
+        self.translator.languages().await
+    }
+
+    async fn detect_language(&self, segments: &[String]) -> Result<Language> {
+        let chars = billed_chars(segments);
+        self.meter(chars, |usage| usage.detection_chars += chars)?;
+        self.translator.detect_language(segments).await
+    }
+
+    async fn translate(
+        &self,
+        segments: &[String],
+        from: Option<&Language>,
+        to: &[Language],
+    ) -> Result<(Language, Vec<Vec<Translation>>)> {
+        let language_chars = to
+            .iter()
+            .map(|language| self.translator.billed_chars(segments, language))
+            .collect::<Result<Vec<_>>>()?;
+        self.meter(language_chars.iter().sum(), |usage| {
+            for (language, chars) in to.iter().zip(language_chars) {
+                *usage.translation_chars.entry(language.clone()).or_default() += chars;
+            }
+        })?;
+        self.translator.translate(segments, from, to).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::translate::PseudoTranslator;
+
+    #[tokio::test]
+    async fn test_usage_and_budget() {
+        let usage = Arc::new(Mutex::new(Usage::default()));
+        let translator = MeteredTranslator::new(Box::new(PseudoTranslator::new()), usage.clone());
+        let segments = ["Hi <span id=\"0\">you</span>.".to_string()];
+        let to = ["en-XA".parse().unwrap(), "ar-XB".parse().unwrap()];
+
+        translator.detect_language(&segments).await.unwrap();
+        translator.translate(&segments, None, &to).await.unwrap();
+        {
+            let usage = usage.lock().unwrap();
+            assert_eq!(usage.detection_chars, 27);
+            assert_eq!(usage.translation_chars[&to[1]], 27);
+            assert_eq!(usage.total(), 81);
+        }
+        let translator = MeteredTranslator::new(Box::new(PseudoTranslator::new()), usage.clone())
+            .with_max_chars(100);
+        let error = translator
+            .translate(&segments, None, &to)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Sending another 54 characters to the translation API would exceed the budget of 100 characters (81 already sent)"
+        );
+        assert_eq!(usage.lock().unwrap().total(), 81);
+    }
+}
@@ -0,0 +1,466 @@
+use super::{
+    in_batches, language_direction, most_likely_language, Language, LanguageInfo, RequestLimits,
+    Translation, Translator,
+};
+use crate::retry::{self, RetryPolicy};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+static DEFAULT_ENDPOINT: &str = "https://api.openai.com/v1";
+static CHAT_COMPLETIONS_PATH: &str = "/chat/completions";
+
+/// Keep batches small, as language models are slow to generate long responses and local servers often have small context windows.
+static REQUEST_LIMITS: RequestLimits = RequestLimits {
+    max_segments: 25,
+    max_chars: 6_000,
+};
+
+/// Language models can translate between many languages, so offer the most widely used ones (tag, English name, native name).
+static LANGUAGES: &[(&str, &str, &str)] = &[
+    ("ar", "Arabic", "العربية"),
+    ("bg", "Bulgarian", "Български"),
+    ("bn", "Bangla", "বাংলা"),
+    ("ca", "Catalan", "Català"),
+    ("cs", "Czech", "Čeština"),
+    ("da", "Danish", "Dansk"),
+    ("de", "German", "Deutsch"),
+    ("el", "Greek", "Ελληνικά"),
+    ("en", "English", "English"),
+    ("es", "Spanish", "Español"),
+    ("et", "Estonian", "Eesti"),
+    ("fa", "Persian", "فارسی"),
+    ("fi", "Finnish", "Suomi"),
+    ("fr", "French", "Français"),
+    ("he", "Hebrew", "עברית"),
+    ("hi", "Hindi", "हिन्दी"),
+    ("hr", "Croatian", "Hrvatski"),
+    ("hu", "Hungarian", "Magyar"),
+    ("id", "Indonesian", "Indonesia"),
+    ("it", "Italian", "Italiano"),
+    ("ja", "Japanese", "日本語"),
+    ("ko", "Korean", "한국어"),
+    ("lt", "Lithuanian", "Lietuvių"),
+    ("lv", "Latvian", "Latviešu"),
+    ("ms", "Malay", "Melayu"),
+    ("nb", "Norwegian", "Norsk Bokmål"),
+    ("nl", "Dutch", "Nederlands"),
+    ("pl", "Polish", "Polski"),
+    ("pt", "Portuguese", "Português"),
+    ("ro", "Romanian", "Română"),
+    ("ru", "Russian", "Русский"),
+    ("sk", "Slovak", "Slovenčina"),
+    ("sl", "Slovenian", "Slovenščina"),
+    ("sr", "Serbian", "Српски"),
+    ("sv", "Swedish", "Svenska"),
+    ("sw", "Swahili", "Kiswahili"),
+    ("ta", "Tamil", "தமிழ்"),
+    ("th", "Thai", "ไทย"),
+    ("tr", "Turkish", "Türkçe"),
+    ("uk", "Ukrainian", "Українська"),
+    ("ur", "Urdu", "اردو"),
+    ("vi", "Vietnamese", "Tiếng Việt"),
+    ("zh-Hans", "Chinese Simplified", "中文 (简体)"),
+    ("zh-Hant", "Chinese Traditional", "中文 (繁體)"),
+];
+
+static TRANSLATE_INSTRUCTIONS: &str = "You translate subtitles. The user sends a JSON object with \
+the target language, the segments to translate (each with an id), and the segments before and \
+after them as context. Translate every segment into the target language, using the context to \
+resolve pronouns, terminology and formality, but do not translate the context itself. Keep any \
+<span> tags, with their id attributes, around the corresponding words of the translation. Respond \
+with only a JSON object of the form {\"source_language\": \"<BCP-47 tag of the source language>\", \
+\"translations\": [{\"id\": <segment id>, \"text\": \"<translation>\"}]}, with exactly one \
+translation for each segment.";
+
+static DETECT_INSTRUCTIONS: &str =
+    "Identify the language of the text the user sends. Respond with \
+only a JSON object of the form {\"language\": \"<BCP-47 tag of the language>\"}.";
+
+#[derive(Debug, Clone, serde::Serialize)]
+struct Message<'a> {
+    role: &'static str,
+    content: &'a str,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+struct ChatRequest<'a> {
+    model: &'a str,
+    messages: [Message<'a>; 2],
+    temperature: f32,
+    response_format: serde_json::Value,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct ChatResponseMessage {
+    content: String,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct ChatResponseChoice {
+    message: ChatResponseMessage,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct ChatResponse {
+    choices: Vec<ChatResponseChoice>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+struct Segment<'a> {
+    id: usize,
+    text: &'a str,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+struct TranslateInput<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source_language: Option<String>,
+    target_language: String,
+    context_before: &'a [String],
+    segments: Vec<Segment<'a>>,
+    context_after: &'a [String],
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct TranslateOutputSegment {
+    id: usize,
+    text: String,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct TranslateOutput {
+    source_language: Option<String>,
+    translations: Vec<TranslateOutputSegment>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+struct DetectOutput {
+    language: Language,
+}
+
+/// Client for a language model behind an OpenAI-compatible chat completions API, such as OpenAI's or a local llama.cpp or vLLM server.
+pub struct OpenAiClient {
+    endpoint: String,
+    model: String,
+    api_key: Option<String>,
+    context_sentences: usize,
+    max_concurrent_requests: usize,
+    retry_policy: RetryPolicy,
+}
+
+impl OpenAiClient {
+    /// Create a client for the model with this name, using OpenAI's API unless given another endpoint. Local servers may not need an API key.
+    pub fn new(model: String, api_key: Option<String>) -> OpenAiClient {
+        OpenAiClient {
+            endpoint: DEFAULT_ENDPOINT.to_string(),
+            model,
+            api_key,
+            context_sentences: 3,
+            max_concurrent_requests: 1,
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+
+    /// Send requests to this endpoint, e.g. `http://localhost:8080/v1` for a local llama.cpp server, rather than OpenAI's.
+    pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = endpoint.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Send this many sentences before and after each batch as context for the translation.
+    pub fn with_context_sentences(mut self, context_sentences: usize) -> Self {
+        self.context_sentences = context_sentences;
+        self
+    }
+
+    /// Retry requests which are throttled or fail with transient errors according to this policy.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Send up to this many requests at once, when the sentences don't fit in a single request.
+    pub fn with_max_concurrent_requests(mut self, max_concurrent_requests: usize) -> Self {
+        self.max_concurrent_requests = max_concurrent_requests.max(1);
+        self
+    }
+
+    /// Send the instructions and input to the model, returning the content of its response.
+    async fn complete(
+        &self,
+        client: &reqwest::Client,
+        instructions: &str,
+        input: &str,
+    ) -> Result<String> {
+        let req_body = ChatRequest {
+            model: &self.model,
+            messages: [
+                Message {
+                    role: "system",
+                    content: instructions,
+                },
+                Message {
+                    role: "user",
+                    content: input,
+                },
+            ],
+            temperature: 0.0,
+            response_format: json!({"type": "json_object"}),
+        };
+        let url = format!("{}{}", self.endpoint, CHAT_COMPLETIONS_PATH);
+        let resp = retry::send(&self.retry_policy, "Chat completions API", || {
+            let request = client.post(&url).json(&req_body);
+            match &self.api_key {
+                Some(api_key) => request.bearer_auth(api_key),
+                None => request,
+            }
+        })
+        .await?;
+        let resp_body = resp
+            .json::<ChatResponse>()
+            .await
+            .with_context(|| "Failed to parse the response from the chat completions API")?;
+        resp_body
+            .choices
+            .into_iter()
+            .next()
+            .map(|c| c.message.content)
+            .with_context(|| "Chat completions API returned no choices")
+    }
+}
+
+/// The JSON in a model's response, which some models put in a Markdown code block despite being asked not to.
+fn json_content(content: &str) -> &str {
+    let content = content.trim();
+    match content.strip_prefix("```") {
+        Some(code_block) => code_block
+            .trim_start_matches("json")
+            .trim_end_matches("```")
+            .trim(),
+        None => content,
+    }
+}
+
+/// Check the model translated each segment with an id in `ids` exactly once, and return the translations in order, with the source language it detected.
+fn collect_translations(content: &str, ids: Range<usize>) -> Result<(Option<String>, Vec<String>)> {
+    let output: TranslateOutput = serde_json::from_str(json_content(content))
+        .with_context(|| format!("Language model returned invalid JSON: {}", content))?;
+    let mut translations: Vec<Option<String>> = vec![None; ids.len()];
+    for segment in output.translations {
+        if !ids.contains(&segment.id) {
+            bail!("Language model returned unknown segment {}", segment.id);
+        }
+        let translation = &mut translations[segment.id - ids.start];
+        if translation.is_some() {
+            bail!(
+                "Language model returned segment {} more than once",
+                segment.id
+            );
+        }
+        *translation = Some(segment.text);
+    }
+    let translations = translations
+        .into_iter()
+        .zip(ids)
+        .map(|(translation, id)| {
+            translation.with_context(|| format!("Language model didn't translate segment {}", id))
+        })
+        .collect::<Result<_>>()?;
+    Ok((output.source_language, translations))
+}
+
+#[async_trait]
+impl Translator for OpenAiClient {
+    fn name(&self) -> &str {
+        "Chat completions"
+    }
+
+    fn options_key(&self) -> String {
+        json!({
+            "endpoint": self.endpoint,
+            "model": self.model,
+            "context_sentences": self.context_sentences,
+        })
+        .to_string()
+    }
+
+    async fn languages(&self) -> Result<BTreeMap<String, LanguageInfo>> {
+        Ok(LANGUAGES
+            .iter()
+            .map(|(code, name, native_name)| {
+                let info = LanguageInfo {
+                    name: name.to_string(),
+                    native_name: native_name.to_string(),
+                    direction: language_direction(code),
+                };
+                (code.to_string(), info)
+            })
+            .collect())
+    }
+
+    async fn detect_language(&self, segments: &[String]) -> Result<Language> {
+        let client = reqwest::Client::new();
+        let content = self
+            .complete(&client, DETECT_INSTRUCTIONS, &segments.join("\n"))
+            .await?;
+        let output: DetectOutput = serde_json::from_str(json_content(&content))
+            .with_context(|| format!("Language model returned invalid JSON: {}", content))?;
+        Ok(output.language)
+    }
+
+    async fn translate(
+        &self,
+        segments: &[String],
+        from: Option<&Language>,
+        to: &[Language],
+    ) -> Result<(Language, Vec<Vec<Translation>>)> {
+        let client = &reqwest::Client::new();
+        let mut translations = vec![];
+        let mut detected_languages = vec![];
+        for language in to {
+            let language_translations: Vec<(Option<String>, String)> = in_batches(
+                segments,
+                REQUEST_LIMITS,
+                1,
+                self.max_concurrent_requests,
+                |batch_start, batch| async move {
+                    let ids = batch_start..batch_start + batch.len();
+                    let context_start = batch_start.saturating_sub(self.context_sentences);
+                    let context_end = (ids.end + self.context_sentences).min(segments.len());
+                    let input = TranslateInput {
+                        source_language: from.map(|l| l.to_string()),
+                        target_language: language.to_string(),
+                        context_before: &segments[context_start..batch_start],
+                        segments: batch
+                            .iter()
+                            .zip(ids.clone())
+                            .map(|(text, id)| Segment { id, text })
+                            .collect(),
+                        context_after: &segments[ids.end..context_end],
+                    };
+                    let content = self
+                        .complete(
+                            client,
+                            TRANSLATE_INSTRUCTIONS,
+                            &serde_json::to_string(&input)?,
+                        )
+                        .await?;
+                    let (source_language, texts) = collect_translations(&content, ids)?;
+                    Ok(texts
+                        .into_iter()
+                        .map(|text| (source_language.clone(), text))
+                        .collect())
+                },
+            )
+            .await?;
+            detected_languages.extend(
+                language_translations
+                    .iter()
+                    .filter_map(|(l, _)| l.as_deref()?.parse::<Language>().ok())
+                    .map(|l| (l, 1.0)),
+            );
+            translations.push(
+                language_translations
+                    .into_iter()
+                    .map(|(_, text)| Translation {
+                        text,
+                        alignment: None,
+                    })
+                    .collect(),
+            );
+        }
+
+        let source_language = match from {
+            Some(source_language) => source_language.clone(),
+            None => most_likely_language(detected_languages).map_or_else(|| "en".parse(), Ok)?,
+        };
+        Ok((source_language, translations))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::matchers::{body_string_contains, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn test_collect_translations() {
+        let content = r#"{"source_language": "en", "translations": [
+            {"id": 4, "text": "Deux"}, {"id": 3, "text": "Un"}
+        ]}"#;
+        assert_eq!(
+            collect_translations(content, 3..5).unwrap(),
+            (
+                Some("en".to_string()),
+                vec!["Un".to_string(), "Deux".to_string()]
+            )
+        );
+        let code_block = format!("```json\n{}\n```", content);
+        assert!(collect_translations(&code_block, 3..5).is_ok());
+
+        let error = |content: &str| collect_translations(content, 3..5).unwrap_err().to_string();
+        assert_eq!(
+            error(r#"{"translations": [{"id": 3, "text": "Un"}]}"#),
+            "Language model didn't translate segment 4"
+        );
+        assert_eq!(
+            error(r#"{"translations": [{"id": 3, "text": "Un"}, {"id": 3, "text": "Un"}]}"#),
+            "Language model returned segment 3 more than once"
+        );
+        assert_eq!(
+            error(r#"{"translations": [{"id": 5, "text": "Trois"}]}"#),
+            "Language model returned unknown segment 5"
+        );
+        assert!(error("Un, deux").starts_with("Language model returned invalid JSON"));
+    }
+
+    #[tokio::test]
+    async fn test_translate_with_context() {
+        let server = MockServer::start().await;
+        // The second batch is sent with the end of the first as context
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .and(header("Authorization", "Bearer key"))
+            .and(body_string_contains(
+                r#"\"context_before\":[\"Sentence 23\",\"Sentence 24\"],\"segments\":[{\"id\":25,"#,
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"choices": [
+                {"message": {"role": "assistant", "content": json!({
+                    "source_language": "en",
+                    "translations": [{"id": 25, "text": "Phrase 25"}]
+                }).to_string()}}
+            ]})))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/v1/chat/completions"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"choices": [
+                {"message": {"role": "assistant", "content": json!({
+                    "source_language": "en",
+                    "translations": (0..25)
+                        .map(|id| json!({"id": id, "text": format!("Phrase {}", id)}))
+                        .collect::<Vec<_>>()
+                }).to_string()}}
+            ]})))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = OpenAiClient::new("model".to_string(), Some("key".to_string()))
+            .with_endpoint(&format!("{}/v1", server.uri()))
+            .with_context_sentences(2);
+        let segments: Vec<String> = (0..26).map(|i| format!("Sentence {}", i)).collect();
+        let (source_language, translations) = client
+            .translate(&segments, None, &["fr".parse().unwrap()])
+            .await
+            .unwrap();
+        assert_eq!(source_language.to_string(), "en");
+        assert_eq!(translations[0].len(), 26);
+        assert_eq!(translations[0][25].text, "Phrase 25");
+    }
+}
@@ -0,0 +1,152 @@
+use super::{language_direction, Direction, Language, LanguageInfo, Translation, Translator};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+
+/// The pseudo-locales, following Android's conventions, and English as the source language.
+static LANGUAGES: &[(&str, &str)] = &[
+    ("en", "English"),
+    ("en-XA", "Pseudo-localised (accented)"),
+    ("ar-XB", "Pseudo-localised (right-to-left)"),
+];
+
+static RIGHT_TO_LEFT_OVERRIDE: char = '\u{202E}';
+static POP_DIRECTIONAL_FORMATTING: char = '\u{202C}';
+
+/// An accented version of an ASCII letter, which is still recognisable.
+fn accented(c: char) -> char {
+    let (plain, accented) = if c.is_ascii_lowercase() {
+        ("abcdefghijklmnopqrstuvwxyz", "áƀçðéƒĝĥîĵķļɱñöþǫŕšţûṽŵẋýž")
+    } else {
+        ("ABCDEFGHIJKLMNOPQRSTUVWXYZ", "ÅƁÇÐÉƑĜĤÎĴĶĻṀÑÖÞǪŔŠŢÛṼŴẊÝŽ")
+    };
+    plain
+        .find(c)
+        .and_then(|i| accented.chars().nth(i))
+        .unwrap_or(c)
+}
+
+/// Pseudo-translate a segment of HTML: accent its letters, double its vowels (making it about a third longer, like many real translations) and put it in brackets, so untranslated and truncated text stands out. Tags and character references are left alone.
+///
+/// Right-to-left pseudo-translations also have each word wrapped in a right-to-left override, so it's displayed reversed.
+fn pseudo_translate(html: &str, right_to_left: bool) -> String {
+    let mut translation = String::from("[");
+    let mut in_word = false;
+    let mut chars = html.chars();
+    while let Some(c) = chars.next() {
+        // Words are runs of letters, digits, apostrophes and character references, leaving punctuation outside the override
+        let is_word_char = c.is_alphanumeric() || c == '\'' || c == '&';
+        if right_to_left && is_word_char != in_word {
+            in_word = is_word_char;
+            translation.push(if in_word {
+                RIGHT_TO_LEFT_OVERRIDE
+            } else {
+                POP_DIRECTIONAL_FORMATTING
+            });
+        }
+        match c {
+            '<' => {
+                translation.push(c);
+                translation.extend(chars.by_ref().take_while(|&c| c != '>'));
+                translation.push('>');
+            }
+            '&' => {
+                translation.push(c);
+                translation.extend(chars.by_ref().take_while(|&c| c != ';'));
+                translation.push(';');
+            }
+            _ => {
+                translation.push(accented(c));
+                if "aeiouAEIOU".contains(c) {
+                    translation.push(accented(c.to_ascii_lowercase()));
+                }
+            }
+        }
+    }
+    if in_word {
+        translation.push(POP_DIRECTIONAL_FORMATTING);
+    }
+    translation.push(']');
+    translation
+}
+
+/// An offline translator which pseudo-localises the text rather than translating it, for previewing the layout of translations and for tests.
+#[derive(Default)]
+pub struct PseudoTranslator {}
+
+impl PseudoTranslator {
+    pub fn new() -> PseudoTranslator {
+        PseudoTranslator {}
+    }
+}
+
+#[async_trait]
+impl Translator for PseudoTranslator {
+    fn name(&self) -> &str {
+        "Pseudo-localisation"
+    }
+
+    async fn languages(&self) -> Result<BTreeMap<String, LanguageInfo>> {
+        Ok(LANGUAGES
+            .iter()
+            .map(|(code, name)| {
+                let info = LanguageInfo {
+                    name: name.to_string(),
+                    native_name: name.to_string(),
+                    direction: language_direction(code),
+                };
+                (code.to_string(), info)
+            })
+            .collect())
+    }
+
+    async fn detect_language(&self, _segments: &[String]) -> Result<Language> {
+        // Pseudo-localisation only works on text in Latin script, so assume it's English
+        "en".parse()
+    }
+
+    async fn translate(
+        &self,
+        segments: &[String],
+        from: Option<&Language>,
+        to: &[Language],
+    ) -> Result<(Language, Vec<Vec<Translation>>)> {
+        let translations = to
+            .iter()
+            .map(|language| {
+                let right_to_left =
+                    matches!(language_direction(&language.to_string()), Direction::Rtl);
+                segments
+                    .iter()
+                    .map(|segment| Translation {
+                        text: pseudo_translate(segment, right_to_left),
+                        alignment: None,
+                    })
+                    .collect()
+            })
+            .collect();
+        let source_language = match from {
+            Some(source_language) => source_language.clone(),
+            None => self.detect_language(segments).await?,
+        };
+        Ok((source_language, translations))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pseudo_translate() {
+        assert_eq!(
+            pseudo_translate("Hello <span id=\"0\">R&amp;D</span> team.", false),
+            "[Ĥééļļöö <span id=\"0\">Ŕ&amp;Ð</span> ţééááɱ.]"
+        );
+        assert_eq!(
+            pseudo_translate("Hi, it's <span id=\"0\">you</span>.", true),
+            "[\u{202E}Ĥîî\u{202C}, \u{202E}îîţ'š\u{202C} <span id=\"0\">\u{202E}ýööûû\u{202C}</span>.]"
+        );
+        assert_eq!(pseudo_translate("", false), "[]");
+    }
+}
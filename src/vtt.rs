@@ -1,18 +1,47 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use regex::Regex;
+use std::fmt;
 use std::fs::File;
-use std::io::{self, BufRead, Write};
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::OnceLock;
 
 #[derive(Debug, Clone)]
 pub struct VttBlock {
-    pub _id: String,
-    pub timecode: String,
+    pub id: Option<String>,
+    pub start: Timestamp,
+    pub end: Timestamp,
+    pub settings: Vec<CueSetting>,
     pub text_lines: Vec<String>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NonCueKind {
+    Note,
+    Style,
+    Region,
+}
+
+/// A NOTE, STYLE or REGION block, kept so that it can be written back out unchanged.
+#[derive(Debug, Clone)]
+pub struct VttNonCueBlock {
+    pub kind: NonCueKind,
+    /// Index of the cue in `Vtt::blocks` that this block precedes.
+    pub before_block: usize,
+    pub lines: Vec<String>,
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct Vtt {
+    /// The header, from the `WEBVTT` line up to the first blank line.
+    pub header_lines: Vec<String>,
     pub blocks: Vec<VttBlock>,
+    pub non_cue_blocks: Vec<VttNonCueBlock>,
+}
+
+enum Block {
+    Cue(VttBlock),
+    NonCue(NonCueKind, Vec<String>),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -21,49 +50,345 @@ pub enum Direction {
     Rtl,
 }
 
+/// A cue timestamp, with millisecond precision.
+#[derive(Debug, Clone, Copy)]
+pub struct Timestamp {
+    millis: u64,
+    // Whether to write the hours even when they are zero, so that timestamps are re-serialised as they were parsed
+    with_hours: bool,
+}
+
+impl Timestamp {
+    pub fn from_millis(millis: u64) -> Timestamp {
+        Timestamp {
+            millis,
+            with_hours: false,
+        }
+    }
+
+    /// A timestamp at another time, written in the same format (with or without hours) as this one.
+    pub fn with_millis(&self, millis: u64) -> Timestamp {
+        Timestamp {
+            millis,
+            with_hours: self.with_hours,
+        }
+    }
+
+    pub fn as_millis(&self) -> u64 {
+        self.millis
+    }
+}
+
+impl PartialEq for Timestamp {
+    fn eq(&self, other: &Self) -> bool {
+        self.millis == other.millis
+    }
+}
+
+impl Eq for Timestamp {}
+
+impl PartialOrd for Timestamp {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Timestamp {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.millis.cmp(&other.millis)
+    }
+}
+
+impl FromStr for Timestamp {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        static TIMESTAMP_RE: OnceLock<Regex> = OnceLock::new();
+        let re = TIMESTAMP_RE.get_or_init(|| {
+            Regex::new(r"^(?:(?P<h>[0-9]+):)?(?P<m>[0-9]{2}):(?P<s>[0-9]{2})\.(?P<ms>[0-9]{3})$")
+                .unwrap()
+        });
+        let caps = re
+            .captures(s)
+            .ok_or_else(|| anyhow!("Invalid timestamp \"{}\"", s))?;
+        let field = |name: &str| -> Result<u64> {
+            caps.name(name)
+                .map_or(Ok(0), |m| m.as_str().parse::<u64>())
+                .with_context(|| format!("Invalid timestamp \"{}\"", s))
+        };
+        let (hours, minutes, seconds, millis) =
+            (field("h")?, field("m")?, field("s")?, field("ms")?);
+        if minutes > 59 || seconds > 59 {
+            return Err(anyhow!("Invalid timestamp \"{}\"", s));
+        }
+        // A huge number of hours would overflow
+        let millis = hours
+            .checked_mul(3_600_000)
+            .and_then(|h| h.checked_add((minutes * 60 + seconds) * 1000 + millis))
+            .ok_or_else(|| anyhow!("Invalid timestamp \"{}\"", s))?;
+        Ok(Timestamp {
+            millis,
+            with_hours: caps.name("h").is_some(),
+        })
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hours = self.millis / 3_600_000;
+        let minutes = self.millis / 60_000 % 60;
+        let seconds = self.millis / 1000 % 60;
+        let millis = self.millis % 1000;
+        if hours > 0 || self.with_hours {
+            write!(f, "{:02}:", hours)?;
+        }
+        write!(f, "{:02}:{:02}.{:03}", minutes, seconds, millis)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Vertical {
+    Rl,
+    Lr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinePosition {
+    Auto,
+    Number(f64),
+    Percentage(f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineAlign {
+    Start,
+    Center,
+    End,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Position {
+    Auto,
+    Percentage(f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PositionAlign {
+    LineLeft,
+    Center,
+    LineRight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Align {
+    Start,
+    Center,
+    End,
+    Left,
+    Right,
+}
+
+/// A single cue setting from the end of a timing line, e.g. `line:10%,end`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CueSetting {
+    Vertical(Vertical),
+    Line(LinePosition, Option<LineAlign>),
+    Position(Position, Option<PositionAlign>),
+    Size(f64),
+    Align(Align),
+    Region(String),
+}
+
+impl CueSetting {
+    /// Parse a cue setting, returning None if it is unrecognised or invalid (which the WebVTT parser algorithm says to ignore).
+    fn parse(setting: &str) -> Option<CueSetting> {
+        let (name, value) = setting.split_once(':')?;
+        let (value, alignment) = match value.split_once(',') {
+            Some((value, alignment)) => (value, Some(alignment)),
+            None => (value, None),
+        };
+        match (name, alignment) {
+            ("vertical", None) => match value {
+                "rl" => Some(CueSetting::Vertical(Vertical::Rl)),
+                "lr" => Some(CueSetting::Vertical(Vertical::Lr)),
+                _ => None,
+            },
+            ("line", _) => {
+                let line = if value == "auto" {
+                    LinePosition::Auto
+                } else if value.ends_with('%') {
+                    LinePosition::Percentage(parse_percentage(value)?)
+                } else {
+                    LinePosition::Number(parse_number(value)?)
+                };
+                let alignment = match alignment {
+                    None => None,
+                    Some("start") => Some(LineAlign::Start),
+                    Some("center") => Some(LineAlign::Center),
+                    Some("end") => Some(LineAlign::End),
+                    Some(_) => return None,
+                };
+                Some(CueSetting::Line(line, alignment))
+            }
+            ("position", _) => {
+                let position = if value == "auto" {
+                    Position::Auto
+                } else {
+                    Position::Percentage(parse_percentage(value)?)
+                };
+                let alignment = match alignment {
+                    None => None,
+                    Some("line-left") => Some(PositionAlign::LineLeft),
+                    Some("center") => Some(PositionAlign::Center),
+                    Some("line-right") => Some(PositionAlign::LineRight),
+                    Some(_) => return None,
+                };
+                Some(CueSetting::Position(position, alignment))
+            }
+            ("size", None) => Some(CueSetting::Size(parse_percentage(value)?)),
+            ("align", None) => match value {
+                "start" => Some(CueSetting::Align(Align::Start)),
+                "center" => Some(CueSetting::Align(Align::Center)),
+                "end" => Some(CueSetting::Align(Align::End)),
+                "left" => Some(CueSetting::Align(Align::Left)),
+                "right" => Some(CueSetting::Align(Align::Right)),
+                _ => None,
+            },
+            ("region", None) if !value.is_empty() && !value.contains("-->") => {
+                Some(CueSetting::Region(value.to_string()))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for CueSetting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CueSetting::Vertical(vertical) => match vertical {
+                Vertical::Rl => write!(f, "vertical:rl"),
+                Vertical::Lr => write!(f, "vertical:lr"),
+            },
+            CueSetting::Line(line, alignment) => {
+                match line {
+                    LinePosition::Auto => write!(f, "line:auto")?,
+                    LinePosition::Number(n) => write!(f, "line:{}", n)?,
+                    LinePosition::Percentage(p) => write!(f, "line:{}%", p)?,
+                }
+                match alignment {
+                    None => Ok(()),
+                    Some(LineAlign::Start) => write!(f, ",start"),
+                    Some(LineAlign::Center) => write!(f, ",center"),
+                    Some(LineAlign::End) => write!(f, ",end"),
+                }
+            }
+            CueSetting::Position(position, alignment) => {
+                match position {
+                    Position::Auto => write!(f, "position:auto")?,
+                    Position::Percentage(p) => write!(f, "position:{}%", p)?,
+                }
+                match alignment {
+                    None => Ok(()),
+                    Some(PositionAlign::LineLeft) => write!(f, ",line-left"),
+                    Some(PositionAlign::Center) => write!(f, ",center"),
+                    Some(PositionAlign::LineRight) => write!(f, ",line-right"),
+                }
+            }
+            CueSetting::Size(size) => write!(f, "size:{}%", size),
+            CueSetting::Align(align) => match align {
+                Align::Start => write!(f, "align:start"),
+                Align::Center => write!(f, "align:center"),
+                Align::End => write!(f, "align:end"),
+                Align::Left => write!(f, "align:left"),
+                Align::Right => write!(f, "align:right"),
+            },
+            CueSetting::Region(region) => write!(f, "region:{}", region),
+        }
+    }
+}
+
+fn parse_percentage(value: &str) -> Option<f64> {
+    let percentage = parse_number(value.strip_suffix('%')?)?;
+    (0.0..=100.0).contains(&percentage).then_some(percentage)
+}
+
+fn parse_number(value: &str) -> Option<f64> {
+    let re = Regex::new(r"^-?[0-9]+(\.[0-9]+)?$").unwrap();
+    re.is_match(value).then(|| value.parse().ok()).flatten()
+}
+
+impl VttBlock {
+    /// The timing line for this cue, e.g. `00:00:05.020 --> 00:00:08.874 align:start`.
+    pub fn timing_line(&self) -> String {
+        let mut line = format!("{} --> {}", self.start, self.end);
+        for setting in self.settings.iter() {
+            line.push_str(&format!(" {}", setting));
+        }
+        line
+    }
+}
+
 impl Vtt {
     pub fn parse<P: AsRef<std::path::Path> + ?Sized + std::fmt::Debug>(path: &P) -> Result<Vtt> {
-        let mut vtt = Vtt::default();
-        let file =
-            File::open(path).with_context(|| format!("Failed to open VTT file {:?}", path))?;
-        let lines = io::BufReader::new(file).lines();
-        let mut block: Option<VttBlock> = None;
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to open VTT file {:?}", path))?;
+        Vtt::parse_str(&contents).with_context(|| format!("Failed to parse VTT file {:?}", path))
+    }
+
+    /// Parse the contents of a WebVTT file, following the W3C WebVTT parser algorithm.
+    pub fn parse_str(contents: &str) -> Result<Vtt> {
+        // Strip any byte order mark and normalise line endings
+        let contents = contents
+            .strip_prefix('\u{FEFF}')
+            .unwrap_or(contents)
+            .replace('\0', "\u{FFFD}")
+            .replace("\r\n", "\n")
+            .replace('\r', "\n");
+        let lines: Vec<&str> = contents.split('\n').collect();
+
+        if !is_signature(lines[0]) {
+            return Err(anyhow!("Missing WEBVTT signature"));
+        }
 
-        for line in lines {
-            let line = line?;
+        // The header runs up to the first blank line or timing line
+        let mut vtt = Vtt::default();
+        let mut position = 0;
+        while position < lines.len() && !is_blank(lines[position]) && !is_timecode(lines[position])
+        {
+            vtt.header_lines.push(lines[position].to_string());
+            position += 1;
+        }
 
-            if is_blank(&line) || line == "WEBVTT" {
-                // Ignore blank lines and header lines
+        while position < lines.len() {
+            if is_blank(lines[position]) {
+                position += 1;
                 continue;
-            } else if is_new_block(&line) {
-                // This line begins a new block - save off the old one
-                if let Some(prev_block) = block {
-                    vtt.blocks.push(prev_block);
+            }
+            match collect_block(&lines, &mut position)? {
+                Some(Block::Cue(block)) => vtt.blocks.push(block),
+                // Style sheets and regions are only valid before the first cue
+                Some(Block::NonCue(kind, lines))
+                    if kind == NonCueKind::Note || vtt.blocks.is_empty() =>
+                {
+                    vtt.non_cue_blocks.push(VttNonCueBlock {
+                        kind,
+                        before_block: vtt.blocks.len(),
+                        lines,
+                    })
                 }
-                block = Some(VttBlock {
-                    _id: line,
-                    timecode: String::from(""),
-                    text_lines: vec![],
-                });
-            } else if is_timecode(&line) {
-                // Timecode line - fill in to current block
-                block = block.map(|mut b| {
-                    b.timecode = line;
-                    b
-                });
-            } else {
-                // This is a text line - append to the current block
-                block = block.map(|mut b| {
-                    b.text_lines.push(line.trim().to_string());
-                    b
-                });
+                _ => {}
             }
         }
+        Ok(vtt)
+    }
 
-        if let Some(last_block) = block {
-            vtt.blocks.push(last_block);
+    /// Update the `Language:` header (if there is one) to the given language.
+    pub fn set_language(&mut self, language: &str) {
+        for line in self.header_lines.iter_mut() {
+            if line.to_lowercase().starts_with("language:") {
+                *line = format!("Language: {}", language);
+            }
         }
-        Ok(vtt)
     }
 
     pub fn write<P: AsRef<std::path::Path> + std::fmt::Debug>(
@@ -73,12 +398,24 @@ impl Vtt {
     ) -> Result<()> {
         let mut vtt_file =
             File::create(path).with_context(|| format!("Failed to create VTT file {:?}", path))?;
+        self.write_to(&mut vtt_file, direction)
+    }
 
-        writeln!(vtt_file, "WEBVTT\n")?;
+    pub fn write_to<W: Write>(&self, vtt_file: &mut W, direction: Direction) -> Result<()> {
+        if self.header_lines.is_empty() {
+            writeln!(vtt_file, "WEBVTT")?;
+        }
+        for line in self.header_lines.iter() {
+            writeln!(vtt_file, "{}", line)?;
+        }
+        writeln!(vtt_file)?;
 
-        for vtt_block in self.blocks.iter() {
-            writeln!(vtt_file, "{}", vtt_block._id)?;
-            writeln!(vtt_file, "{}", vtt_block.timecode)?;
+        for (block_num, vtt_block) in self.blocks.iter().enumerate() {
+            self.write_non_cue_blocks(vtt_file, |before_block| before_block == block_num)?;
+            if let Some(id) = &vtt_block.id {
+                writeln!(vtt_file, "{}", id)?;
+            }
+            writeln!(vtt_file, "{}", vtt_block.timing_line())?;
             for line in vtt_block.text_lines.iter() {
                 let mut line = line.trim().to_string();
                 if direction == Direction::Rtl {
@@ -95,6 +432,24 @@ impl Vtt {
             }
             writeln!(vtt_file)?;
         }
+        self.write_non_cue_blocks(vtt_file, |before_block| before_block >= self.blocks.len())
+    }
+
+    fn write_non_cue_blocks<W: Write, F: Fn(usize) -> bool>(
+        &self,
+        vtt_file: &mut W,
+        filter: F,
+    ) -> Result<()> {
+        for non_cue_block in self
+            .non_cue_blocks
+            .iter()
+            .filter(|b| filter(b.before_block))
+        {
+            for line in non_cue_block.lines.iter() {
+                writeln!(vtt_file, "{}", line)?;
+            }
+            writeln!(vtt_file)?;
+        }
         Ok(())
     }
 }
@@ -104,18 +459,90 @@ fn is_blank(line: &str) -> bool {
     re.is_match(line)
 }
 
-fn is_new_block(line: &str) -> bool {
-    let re = Regex::new(
-        r"^[0-9a-fA-F]{8}\b-[0-9a-fA-F]{4}\b-[0-9a-fA-F]{4}\b-[0-9a-fA-F]{4}\b-[0-9a-fA-F]{12}",
-    )
-    .unwrap();
-    re.is_match(line)
+fn is_signature(line: &str) -> bool {
+    line == "WEBVTT" || line.starts_with("WEBVTT ") || line.starts_with("WEBVTT\t")
 }
 
 fn is_timecode(line: &str) -> bool {
     line.contains("-->")
 }
 
+fn non_cue_kind(line: &str) -> Option<NonCueKind> {
+    let keyword = line.split([' ', '\t']).next().unwrap_or("");
+    match keyword {
+        "NOTE" => Some(NonCueKind::Note),
+        "STYLE" => Some(NonCueKind::Style),
+        "REGION" => Some(NonCueKind::Region),
+        _ => None,
+    }
+}
+
+/// Collect the block starting at `position`, returning it if it is a cue, comment, style sheet or region.
+///
+/// On return `position` is left at the blank line ending the block, or at a timing line which begins the next one.
+fn collect_block(lines: &[&str], position: &mut usize) -> Result<Option<Block>> {
+    let mut buffer: Vec<String> = vec![];
+    let mut block: Option<VttBlock> = None;
+    let mut line_count = 0;
+
+    while *position < lines.len() {
+        let line = lines[*position];
+        line_count += 1;
+
+        if is_blank(line) {
+            break;
+        } else if is_timecode(line) {
+            if block.is_some() || line_count > 2 {
+                // A timing line can only appear as the first or second line of a block, so this one starts a new block
+                break;
+            }
+            // Any line preceding the timing line is the cue identifier
+            let (start, end, settings) = parse_timing_line(line)
+                .with_context(|| format!("Invalid cue timings on line {}", *position + 1))?;
+            block = Some(VttBlock {
+                id: buffer.pop(),
+                start,
+                end,
+                settings,
+                text_lines: vec![],
+            });
+        } else {
+            buffer.push(line.to_string());
+        }
+        *position += 1;
+    }
+
+    match block {
+        Some(mut block) => {
+            block.text_lines = buffer;
+            Ok(Some(Block::Cue(block)))
+        }
+        None => Ok(buffer
+            .first()
+            .and_then(|line| non_cue_kind(line))
+            .map(|kind| Block::NonCue(kind, buffer))),
+    }
+}
+
+/// Parse a timing line into its start and end timestamps and cue settings.
+fn parse_timing_line(line: &str) -> Result<(Timestamp, Timestamp, Vec<CueSetting>)> {
+    let (start, rest) = line
+        .split_once("-->")
+        .ok_or_else(|| anyhow!("Missing \"-->\" in timing line"))?;
+    let mut rest = rest.split_whitespace();
+    let start = Timestamp::from_str(start.trim())?;
+    let end = Timestamp::from_str(rest.next().unwrap_or(""))?;
+    if end < start {
+        return Err(anyhow!(
+            "Cue end time {} is before its start time {}",
+            end,
+            start
+        ));
+    }
+    let settings = rest.filter_map(CueSetting::parse).collect();
+    Ok((start, end, settings))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -131,7 +558,167 @@ mod tests {
     }
 
     #[test]
-    fn test_is_new_block() {
-        assert!(is_new_block("f9e6254d-71b5-400f-bdcc-802831ce24f4-0"));
+    fn test_is_signature() {
+        assert!(is_signature("WEBVTT"));
+        assert!(is_signature("WEBVTT - Some title"));
+        assert!(!is_signature("WEBVTTX"));
+    }
+
+    #[test]
+    fn test_parse_uuid_ids() {
+        let vtt = Vtt::parse_str(
+            "WEBVTT\n\nf9e6254d-71b5-400f-bdcc-802831ce24f4-0\n00:00:05.020 --> 00:00:08.874\nHello there.\n",
+        )
+        .unwrap();
+        assert_eq!(vtt.blocks.len(), 1);
+        assert_eq!(
+            vtt.blocks[0].id.as_deref(),
+            Some("f9e6254d-71b5-400f-bdcc-802831ce24f4-0")
+        );
+        assert_eq!(vtt.blocks[0].timing_line(), "00:00:05.020 --> 00:00:08.874");
+        assert_eq!(vtt.blocks[0].text_lines, vec!["Hello there."]);
+    }
+
+    #[test]
+    fn test_parse_numeric_and_missing_ids() {
+        let vtt = Vtt::parse_str(
+            "WEBVTT\r\n\r\n1\r\n00:01.000 --> 00:02.000\r\nOne\r\nTwo\r\n\r\n00:03.000 --> 00:04.000\r\nThree\r\n",
+        )
+        .unwrap();
+        assert_eq!(vtt.blocks.len(), 2);
+        assert_eq!(vtt.blocks[0].id.as_deref(), Some("1"));
+        assert_eq!(vtt.blocks[0].text_lines, vec!["One", "Two"]);
+        assert_eq!(vtt.blocks[1].id, None);
+        assert_eq!(vtt.blocks[1].start, Timestamp::from_millis(3000));
+        assert_eq!(vtt.blocks[1].timing_line(), "00:03.000 --> 00:04.000");
+        assert_eq!(vtt.blocks[1].text_lines, vec!["Three"]);
+    }
+
+    #[test]
+    fn test_parse_skips_header_and_non_cue_blocks() {
+        let vtt = Vtt::parse_str(
+            "\u{FEFF}WEBVTT\nKind: captions\n\nNOTE a comment\n\n00:01.000 --> 00:02.000\nOne\n00:03.000 --> 00:04.000\nTwo",
+        )
+        .unwrap();
+        assert_eq!(vtt.blocks.len(), 2);
+        assert_eq!(vtt.blocks[0].text_lines, vec!["One"]);
+        assert_eq!(vtt.blocks[1].id, None);
+        assert_eq!(vtt.blocks[1].text_lines, vec!["Two"]);
+    }
+
+    #[test]
+    fn test_non_cue_kind() {
+        assert_eq!(non_cue_kind("NOTE"), Some(NonCueKind::Note));
+        assert_eq!(non_cue_kind("NOTE\tcomment"), Some(NonCueKind::Note));
+        assert_eq!(non_cue_kind("STYLE"), Some(NonCueKind::Style));
+        assert_eq!(non_cue_kind("REGION"), Some(NonCueKind::Region));
+        assert_eq!(non_cue_kind("NOTES"), None);
+    }
+
+    #[test]
+    fn test_non_cue_blocks_round_trip() {
+        let contents = "WEBVTT - Title\nKind: captions\nLanguage: en\n\nSTYLE\n::cue { color: yellow }\n\nREGION\nid:fred\n\nNOTE first\n\n00:01.000 --> 00:02.000 region:fred\nOne\n\nNOTE\nmiddle\ncomment\n\n00:03.000 --> 00:04.000\nTwo\n\nNOTE last\n\n";
+        let mut vtt = Vtt::parse_str(contents).unwrap();
+        assert_eq!(vtt.non_cue_blocks.len(), 5);
+        assert_eq!(vtt.non_cue_blocks[3].before_block, 1);
+        assert_eq!(
+            vtt.non_cue_blocks[3].lines,
+            vec!["NOTE", "middle", "comment"]
+        );
+
+        let mut written = vec![];
+        vtt.write_to(&mut written, Direction::Ltr).unwrap();
+        assert_eq!(String::from_utf8(written).unwrap(), contents);
+
+        vtt.set_language("fa");
+        let mut written = vec![];
+        vtt.write_to(&mut written, Direction::Ltr).unwrap();
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            contents.replace("Language: en", "Language: fa")
+        );
+    }
+
+    #[test]
+    fn test_style_after_cue_is_dropped() {
+        let vtt =
+            Vtt::parse_str("WEBVTT\n\n00:01.000 --> 00:02.000\nOne\n\nSTYLE\n::cue {}\n").unwrap();
+        assert!(vtt.non_cue_blocks.is_empty());
+    }
+
+    #[test]
+    fn test_parse_missing_signature() {
+        assert!(Vtt::parse_str("00:01.000 --> 00:02.000\nOne\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(
+            Timestamp::from_str("01:02:03.004").unwrap().as_millis(),
+            3_723_004
+        );
+        assert_eq!(
+            Timestamp::from_str("02:03.004").unwrap().as_millis(),
+            123_004
+        );
+        assert_eq!(
+            Timestamp::from_str("100:00:00.000").unwrap().to_string(),
+            "100:00:00.000"
+        );
+        assert_eq!(
+            Timestamp::from_str("00:02:03.004").unwrap().to_string(),
+            "00:02:03.004"
+        );
+        assert_eq!(
+            Timestamp::from_millis(3_723_004).to_string(),
+            "01:02:03.004"
+        );
+        assert!(Timestamp::from_str("00:60.000").is_err());
+        assert!(Timestamp::from_str("00:01.00").is_err());
+        // Any number of hour digits is allowed
+        assert_eq!(
+            Timestamp::from_str("1:00:01.000").unwrap(),
+            Timestamp::from_millis(3_601_000)
+        );
+        assert!(Timestamp::from_str("999999999999999999:00:00.000").is_err());
+        assert!(
+            Vtt::parse_str("WEBVTT\n\n999999999999999999:00:00.000 --> 00:01.000\nHi\n").is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_cue_settings() {
+        let (_, _, settings) = parse_timing_line(
+            "00:01.000 --> 00:02.000 vertical:rl line:-1 position:10%,line-left size:35.5% align:end region:fred bogus:1 line:5%,middle",
+        )
+        .unwrap();
+        assert_eq!(
+            settings,
+            vec![
+                CueSetting::Vertical(Vertical::Rl),
+                CueSetting::Line(LinePosition::Number(-1.0), None),
+                CueSetting::Position(Position::Percentage(10.0), Some(PositionAlign::LineLeft)),
+                CueSetting::Size(35.5),
+                CueSetting::Align(Align::End),
+                CueSetting::Region("fred".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_timings() {
+        assert!(parse_timing_line("00:01.000 --> ").is_err());
+        assert!(parse_timing_line("00:01.000 -> 00:02.000").is_err());
+        assert!(parse_timing_line("00:02.000 --> 00:01.000").is_err());
+        assert!(Vtt::parse_str("WEBVTT\n\n00:01.000 --> 00:0x.000\nOne\n").is_err());
+    }
+
+    #[test]
+    fn test_write_round_trip() {
+        let contents = "WEBVTT\n\n1\n00:01.000 --> 00:02.500 line:10%,end align:start\nOne\nTwo\n\n01:00:03.000 --> 01:00:04.000\nThree\n\n";
+        let vtt = Vtt::parse_str(contents).unwrap();
+        let mut written = vec![];
+        vtt.write_to(&mut written, Direction::Ltr).unwrap();
+        assert_eq!(String::from_utf8(written).unwrap(), contents);
     }
 }
@@ -0,0 +1,97 @@
+//! Golden-file tests of the whole pipeline, translating with the offline pseudo-localisation backend.
+//!
+//! Set `UPDATE_GOLDEN=1` to rewrite the golden files from the current output.
+
+use clap::Parser;
+use std::path::{Path, PathBuf};
+use vtt_translate::Cli;
+
+/// Translate `tests/data/{input}` with the extra arguments, and compare the output with `tests/golden/{golden}`.
+async fn check_golden(input: &str, golden: &str, args: &[&str]) {
+    let tests_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
+    let tmp_dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    // Keep the cached list of languages out of the user's cache directory
+    std::env::set_var("XDG_CACHE_HOME", tmp_dir.join("cache"));
+
+    let output_file = tmp_dir.join(golden);
+    let source_vtt_file = tests_dir.join("data").join(input);
+    let mut cli_args = vec![
+        "vtt-translate",
+        "--backend",
+        "pseudo",
+        "--source-vtt-file",
+        source_vtt_file.to_str().unwrap(),
+        "--target-vtt-file",
+        output_file.to_str().unwrap(),
+    ];
+    cli_args.extend(args);
+    vtt_translate::run(Cli::parse_from(cli_args)).await.unwrap();
+
+    let output = std::fs::read_to_string(&output_file).unwrap();
+    let golden_file = tests_dir.join("golden").join(golden);
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::write(&golden_file, &output).unwrap();
+    }
+    let expected = std::fs::read_to_string(&golden_file).unwrap_or_else(|e| {
+        panic!(
+            "Failed to read {:?} ({}); set UPDATE_GOLDEN=1 to create it",
+            golden_file, e
+        )
+    });
+    assert_eq!(output, expected, "Output differs from {:?}", golden_file);
+}
+
+#[tokio::test]
+async fn test_accented() {
+    check_golden(
+        "sample.vtt",
+        "sample.en-XA.vtt",
+        &["--target-language", "en-XA"],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_right_to_left() {
+    check_golden(
+        "sample.vtt",
+        "sample.ar-XB.vtt",
+        &["--target-language", "ar-XB"],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_layout_and_retime() {
+    check_golden(
+        "sample.vtt",
+        "sample.en-XA.retimed.vtt",
+        &[
+            "--source-language",
+            "en",
+            "--target-language",
+            "en-XA",
+            "--max-line-chars",
+            "32",
+            "--retime",
+        ],
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn test_protected_text() {
+    check_golden(
+        "protect.vtt",
+        "protect.en-XA.vtt",
+        &[
+            "--target-language",
+            "en-XA",
+            "--protect",
+            "Jürgen",
+            "--protect-builtin",
+            "url,email,code,version",
+        ],
+    )
+    .await;
+}